@@ -0,0 +1,118 @@
+// Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
+// Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE_MIT or LICENSE_APACHE files.
+
+//! Compile-time validated Accumulo Access Expressions.
+
+use std::ops::Range;
+
+use accumulo_access::{AuthorizationExpression, Lexer, Parser};
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
+use syn::{parse_macro_input, LitStr};
+
+/// Parses an access expression literal at compile time, and expands to a
+/// `&'static AuthorizationExpression` built from the parsed tree.
+///
+/// The tree owns its labels (`String`s in `Vec`s), which can't be built in a `const` or `static`
+/// initializer, so the expansion keeps it in a `static` [`LazyLock`](std::sync::LazyLock): it is
+/// built once, on first use, without parsing the text again, and then shared. `LazyLock` needs
+/// Rust 1.80 or later.
+///
+/// Malformed expressions are compile errors, pointing at the character the parser stopped at
+/// where the compiler supports spans within a literal, and at the whole literal otherwise.
+///
+/// # Example
+/// ```
+/// use std::collections::HashSet;
+/// use accumulo_access::AuthorizationExpression;
+/// use accumulo_access_macros::visibility;
+///
+/// let expr: &'static AuthorizationExpression = visibility!("A&(B|\"c d\")");
+/// assert!(expr.evaluate(&HashSet::from(["A".to_string(), "c d".to_string()])));
+/// ```
+///
+/// Mixing operators is rejected:
+/// ```compile_fail
+/// let expr = accumulo_access_macros::visibility!("A&B|C");
+/// ```
+#[proc_macro]
+pub fn visibility(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let literal = parse_macro_input!(input as LitStr);
+    let expression = literal.value();
+    let mut parser = Parser::new(Lexer::new(&expression));
+    match parser.parse() {
+        Ok(expr) => {
+            let tree = tree_tokens(&expr);
+            quote! {{
+                static VISIBILITY: ::std::sync::LazyLock<::accumulo_access::AuthorizationExpression> =
+                    ::std::sync::LazyLock::new(|| #tree);
+                &*VISIBILITY
+            }}
+            .into()
+        }
+        Err(error) => {
+            let span = error_span(&literal, &expression, parser.position()).unwrap_or_else(|| literal.span());
+            syn::Error::new(span, format!("invalid access expression: {}", error))
+                .to_compile_error()
+                .into()
+        }
+    }
+}
+
+fn tree_tokens(expr: &AuthorizationExpression) -> TokenStream {
+    match expr {
+        AuthorizationExpression::Nil => quote!(::accumulo_access::AuthorizationExpression::Nil),
+        AuthorizationExpression::AccessToken(token) => {
+            quote!(::accumulo_access::AuthorizationExpression::AccessToken(::std::string::String::from(#token)))
+        }
+        AuthorizationExpression::ConjunctionOf(nodes) => {
+            let nodes = nodes.iter().map(tree_tokens);
+            quote!(::accumulo_access::AuthorizationExpression::ConjunctionOf(::std::vec![#(#nodes),*]))
+        }
+        AuthorizationExpression::DisjunctionOf(nodes) => {
+            let nodes = nodes.iter().map(tree_tokens);
+            quote!(::accumulo_access::AuthorizationExpression::DisjunctionOf(::std::vec![#(#nodes),*]))
+        }
+    }
+}
+
+/// The span of the character at `position` (counting from 1) within the literal.
+///
+/// Only some compilers support spans within a literal (currently nightly); `None` falls back to
+/// the whole literal.
+fn error_span(literal: &LitStr, expression: &str, position: usize) -> Option<Span> {
+    let token = literal.token();
+    token.subspan(source_range(&token.to_string(), expression, position)?)
+}
+
+/// The byte range of the character at `position` (counting from 1) of the expression in the
+/// source of its literal. Only plain literals (no escapes, not raw) map characters directly to
+/// the source.
+fn source_range(source: &str, expression: &str, position: usize) -> Option<Range<usize>> {
+    if source != format!("\"{}\"", expression) {
+        return None;
+    }
+    // The source has the opening quote in front.
+    let (offset, c) = expression.char_indices().nth(position.checked_sub(1)?)?;
+    Some(1 + offset..1 + offset + c.len_utf8())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(r#""A&B|C""#, "A&B|C", 4, Some(4..5))]
+    #[case(r#""🕺&[""#, "🕺&[", 3, Some(6..7))]
+    #[case(r#""A&B|C""#, "A&B|C", 0, None)]
+    #[case(r#""A&B|C""#, "A&B|C", 6, None)]
+    #[case(r#"r"A&B|C""#, "A&B|C", 4, None)]
+    #[case(r#""\"a\"&B|C""#, "\"a\"&B|C", 6, None)]
+    fn positions_map_into_plain_literals(#[case] source: &str, #[case] expression: &str, #[case] position: usize, #[case] range: Option<Range<usize>>) {
+        assert_eq!(source_range(source, expression, position), range);
+        if let Some(range) = range {
+            assert_eq!(&source[range], expression.chars().nth(position - 1).unwrap().to_string());
+        }
+    }
+}
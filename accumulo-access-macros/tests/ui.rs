@@ -0,0 +1,8 @@
+// Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
+// Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE_MIT or LICENSE_APACHE files.
+
+// The expected spans are those of a stable compiler, which can't point within a literal.
+#[test]
+fn malformed_expressions_are_compile_errors() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,3 @@
+fn main() {
+    let _ = accumulo_access_macros::visibility!(A & B);
+}
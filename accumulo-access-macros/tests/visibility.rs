@@ -0,0 +1,29 @@
+// Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
+// Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE_MIT or LICENSE_APACHE files.
+
+use std::collections::HashSet;
+
+use accumulo_access::{AuthorizationExpression, Lexer, Parser};
+use accumulo_access_macros::visibility;
+
+fn parse(expression: &str) -> AuthorizationExpression {
+    Parser::new(Lexer::new(expression)).parse().unwrap()
+}
+
+#[test]
+fn expands_to_the_parsed_tree() {
+    assert_eq!(visibility!(""), &parse(""));
+    assert_eq!(visibility!("A"), &parse("A"));
+    assert_eq!(visibility!("A&(B|C)"), &parse("A&(B|C)"));
+    assert_eq!(visibility!("\"say \\\"hi\\\"\"|\"🕺\""), &parse("\"say \\\"hi\\\"\"|\"🕺\""));
+    assert_eq!(visibility!(r#"(a.b:c/d|"e f")&G"#), &parse(r#"(a.b:c/d|"e f")&G"#));
+}
+
+#[test]
+fn is_built_once() {
+    fn expression() -> &'static AuthorizationExpression {
+        visibility!("A|B")
+    }
+    assert!(std::ptr::eq(expression(), expression()));
+    assert!(expression().evaluate(&HashSet::from(["B".to_string()])));
+}
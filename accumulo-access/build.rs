@@ -0,0 +1,16 @@
+// Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
+// Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE_MIT or LICENSE_APACHE files.
+
+fn main() {
+    #[cfg(feature = "protobuf")]
+    {
+        println!("cargo:rerun-if-changed=proto/accumulo_access.proto");
+        // The vendored protoc keeps code generation working without a system-wide installation.
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("vendored protoc is available");
+        prost_build::Config::new()
+            .protoc_executable(protoc)
+            .compile_protos(&["proto/accumulo_access.proto"], &["proto"])
+            .expect("accumulo_access.proto compiles");
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+}
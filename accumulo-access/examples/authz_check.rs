@@ -11,17 +11,7 @@ fn main() {
     args.next();
     let expression = args.next().expect("Missing expression");
     let tokens = args.next().expect("Missing tokens");
-    let json = match args.next() {
-        Some(last) => {
-            // if last == '--json'
-            if last == "--json" {
-                true
-            } else {
-                false
-            }
-        }
-        None => false,
-    };
+    let json = matches!(args.next(), Some(last) if last == "--json");
     let authorized_tokens: HashSet<String> =
         tokens.split(',').map(|token| token.to_string()).collect();
 
@@ -9,7 +9,7 @@ fn main() {
 
     match check_authorization(expression, tokens) {
         Ok(result) => {
-            assert_eq!(result, true);
+            assert!(result);
         }
         Err(e) => println!("Unexpected error {}", e),
     };
@@ -0,0 +1,245 @@
+// Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
+// Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE_MIT or LICENSE_APACHE files.
+
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use crate::json::validate_tree;
+use crate::{for_each_label, AuthorizationExpression, Authorizations, JsonError, Lexer, Parser, ParserError};
+
+/// `AccessExpression` is a validated access expression, keeping the original text.
+///
+/// The text is validated on construction without building a tree, and only parsed into an
+/// [`AuthorizationExpression`] when the tree is first needed (e.g. by [`AccessExpression::evaluate`]),
+/// so values that are only stored and passed along never pay for the tree. Two expressions are equal
+/// when their texts are.
+///
+/// # Example
+/// ```
+/// use std::collections::HashSet;
+/// use accumulo_access::AccessExpression;
+///
+/// let expression = AccessExpression::of("B&A&B").unwrap();
+/// assert_eq!(expression.as_str(), "B&A&B");
+/// assert_eq!(expression.normalize().as_str(), "A&B");
+/// assert!(expression.evaluate(&HashSet::from(["A".to_string(), "B".to_string()])));
+/// assert!(AccessExpression::of("A&B|C").is_err());
+/// ```
+#[derive(Debug, Clone)]
+pub struct AccessExpression {
+    expression: String,
+    tree: OnceLock<AuthorizationExpression>,
+}
+
+impl AccessExpression {
+    /// Creates an `AccessExpression` from the given text, if it is a valid expression.
+    pub fn of(expression: impl Into<String>) -> Result<Self, ParserError> {
+        let expression = expression.into();
+        Self::validate(&expression)?;
+        Ok(AccessExpression {
+            expression,
+            tree: OnceLock::new(),
+        })
+    }
+
+    /// Checks that the text is a valid expression.
+    pub fn validate(expression: &str) -> Result<(), ParserError> {
+        Parser::new(Lexer::new(expression)).validate()
+    }
+
+    /// The text of the expression, as given.
+    pub fn as_str(&self) -> &str {
+        &self.expression
+    }
+
+    /// The parsed expression tree.
+    pub fn tree(&self) -> &AuthorizationExpression {
+        self.tree.get_or_init(|| {
+            Parser::new(Lexer::new(&self.expression))
+                .parse()
+                .expect("the expression was validated on construction")
+        })
+    }
+
+    /// Returns the expression with its scopes sorted and duplicates removed, see
+    /// [`AuthorizationExpression::normalize`].
+    pub fn normalize(&self) -> AccessExpression {
+        let mut tree = self.tree().clone();
+        tree.normalize();
+        AccessExpression::from_parsed(tree)
+    }
+
+    /// The distinct labels referenced by the expression, read from the text without parsing it.
+    ///
+    /// # Example
+    /// ```
+    /// use accumulo_access::{AccessExpression, Authorizations};
+    ///
+    /// let expression = AccessExpression::of("A&(B|\"c d\"|A)").unwrap();
+    /// let labels = Authorizations::of(&["A".to_string(), "B".to_string(), "c d".to_string()]);
+    /// assert_eq!(expression.get_authorizations(), labels);
+    /// ```
+    pub fn get_authorizations(&self) -> Authorizations {
+        let mut labels = HashSet::new();
+        for_each_label(&self.expression, |label| {
+            if !labels.contains(label.as_ref()) {
+                labels.insert(label.into_owned());
+            }
+        })
+        .expect("the expression was validated on construction");
+        Authorizations::of(&labels.into_iter().collect::<Vec<_>>())
+    }
+
+    /// Evaluate the expression with the given set of authorizations, see [`AuthorizationExpression::evaluate`].
+    pub fn evaluate(&self, authorizations: &HashSet<String>) -> bool {
+        self.tree().evaluate_parsed_from(Some(&self.expression), authorizations)
+    }
+
+    /// Returns the text of the expression.
+    pub fn into_string(self) -> String {
+        self.expression
+    }
+
+    /// Writes out a tree that came from the `Parser`, keeping it.
+    fn from_parsed(tree: AuthorizationExpression) -> Self {
+        AccessExpression {
+            expression: tree.to_expression_str(),
+            tree: OnceLock::from(tree),
+        }
+    }
+}
+
+/// Creates the expression's text from the tree, keeping the tree. The tree is validated like the
+/// trees read by [`AuthorizationExpression::from_json`], so its text evaluates like the tree does.
+impl TryFrom<AuthorizationExpression> for AccessExpression {
+    type Error = JsonError;
+
+    fn try_from(tree: AuthorizationExpression) -> Result<Self, Self::Error> {
+        validate_tree(&tree)?;
+        Ok(AccessExpression::from_parsed(tree))
+    }
+}
+
+impl FromStr for AccessExpression {
+    type Err = ParserError;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        Self::of(expression)
+    }
+}
+
+impl TryFrom<&str> for AccessExpression {
+    type Error = ParserError;
+
+    fn try_from(expression: &str) -> Result<Self, Self::Error> {
+        Self::of(expression)
+    }
+}
+
+impl TryFrom<String> for AccessExpression {
+    type Error = ParserError;
+
+    fn try_from(expression: String) -> Result<Self, Self::Error> {
+        Self::of(expression)
+    }
+}
+
+impl AsRef<str> for AccessExpression {
+    fn as_ref(&self) -> &str {
+        &self.expression
+    }
+}
+
+impl Display for AccessExpression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+impl PartialEq for AccessExpression {
+    fn eq(&self, other: &Self) -> bool {
+        self.expression == other.expression
+    }
+}
+
+impl Eq for AccessExpression {}
+
+impl Hash for AccessExpression {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.expression.hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    fn label(label: &str) -> AuthorizationExpression {
+        AuthorizationExpression::AccessToken(label.to_string())
+    }
+
+    #[rstest]
+    #[case("")]
+    #[case("A")]
+    #[case("(A|B)&\"c d\"")]
+    fn valid_expressions_keep_their_text(#[case] text: &str) {
+        let expression: AccessExpression = text.parse().unwrap();
+        assert_eq!(expression.as_str(), text);
+        assert_eq!(expression.to_string(), text);
+        assert_eq!(expression.into_string(), text);
+    }
+
+    #[rstest]
+    #[case("A&B|C")]
+    #[case("A(B)")]
+    #[case("A[")]
+    #[case("A&\"\"")]
+    fn invalid_expressions_are_rejected(#[case] text: &str) {
+        let error = AccessExpression::validate(text).unwrap_err();
+        assert_eq!(Parser::new(Lexer::new(text)).parse(), Err(error.clone()));
+        assert_eq!(AccessExpression::try_from(text), Err(error.clone()));
+        assert_eq!(AccessExpression::try_from(text.to_string()), Err(error));
+    }
+
+    #[test]
+    fn tree_is_parsed_lazily() {
+        let expression = AccessExpression::of("A&B").unwrap();
+        assert_eq!(expression.get_authorizations(), Authorizations::of(&["A".to_string(), "B".to_string()]));
+        assert!(expression.tree.get().is_none());
+        assert!(!expression.evaluate(&HashSet::from(["A".to_string()])));
+        assert!(expression.tree.get().is_some());
+    }
+
+    #[test]
+    fn trees_are_validated() {
+        let expression = AccessExpression::try_from(AuthorizationExpression::DisjunctionOf(vec![])).unwrap_err();
+        assert_eq!(expression.to_string(), "Invalid expression tree at /or: expected at least one operand");
+        let tree = Parser::new(Lexer::new("A|\"b c\"")).parse().unwrap();
+        let expression = AccessExpression::try_from(tree.clone()).unwrap();
+        assert_eq!(expression, AccessExpression::of("\"b c\"|A").unwrap());
+        assert_eq!(expression.tree(), &tree);
+    }
+
+    // The text of a tree is accepted exactly when the tree is.
+    #[rstest]
+    #[case(AuthorizationExpression::ConjunctionOf(vec![label("A"), label("b c")]), true)]
+    #[case(AuthorizationExpression::ConjunctionOf(vec![label("A"), label("")]), false)]
+    #[case(label(""), false)]
+    #[case(AuthorizationExpression::DisjunctionOf(vec![label("A"), AuthorizationExpression::ConjunctionOf(vec![label("")])]), false)]
+    fn text_and_tree_are_validated_alike(#[case] tree: AuthorizationExpression, #[case] valid: bool) {
+        assert_eq!(AccessExpression::of(tree.to_expression_str()).is_ok(), valid);
+        assert_eq!(AccessExpression::try_from(tree).is_ok(), valid);
+    }
+
+    #[test]
+    fn normalize_keeps_the_tree() {
+        let normalized = AccessExpression::of("(C|B)&A&A").unwrap().normalize();
+        assert_eq!(normalized.as_str(), "(B|C)&A");
+        assert!(normalized.tree.get().is_some());
+        assert_eq!(normalized, AccessExpression::of("(B|C)&A").unwrap());
+    }
+}
@@ -0,0 +1,342 @@
+// Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
+// Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE_MIT or LICENSE_APACHE files.
+
+use std::collections::{BTreeSet, HashSet};
+
+use crate::authorization_expression::AuthorizationExpression;
+use crate::authorizations::Authorizations;
+use crate::normal_form::NormalFormError;
+
+/// The maximum number of terms of the disjunctive normal forms that equivalence and implication
+/// checks expand expressions into. Absorbing redundant terms takes quadratic time, so this is
+/// lower than [`DEFAULT_NORMAL_FORM_LIMIT`](crate::DEFAULT_NORMAL_FORM_LIMIT).
+pub const ANALYSIS_DNF_LIMIT: usize = 1024;
+
+/// A conjunction of access tokens; one term of a disjunctive normal form.
+pub(crate) type Term = BTreeSet<String>;
+
+/// Computes the canonical minimal DNF of a (monotone) expression, failing if more than
+/// [`ANALYSIS_DNF_LIMIT`] terms would be produced.
+///
+/// Every term is minimal (no term is a superset of another), and the terms are sorted,
+/// so two expressions are equivalent iff their minimal DNFs are equal.
+pub(crate) fn minimal_dnf(expr: &AuthorizationExpression) -> Result<Vec<Term>, NormalFormError> {
+    minimal_normal_form(expr, true, Some(ANALYSIS_DNF_LIMIT)).ok_or(NormalFormError::LimitExceeded(ANALYSIS_DNF_LIMIT))
+}
+
+/// Computes the minimal DNF (`disjunctive == true`) or CNF of a (monotone) expression.
+///
+/// For the CNF, each returned set is a clause of alternatives. Returns `None` if more than
+/// `limit` terms or clauses would be produced at any step, before producing them.
+pub(crate) fn minimal_normal_form(expr: &AuthorizationExpression, disjunctive: bool, limit: Option<usize>) -> Option<Vec<Term>> {
+    let exceeds_limit = |len: usize| limit.is_some_and(|limit| len > limit);
+    let nodes = match expr {
+        AuthorizationExpression::Nil if disjunctive => return Some(vec![Term::new()]),
+        AuthorizationExpression::Nil => return Some(vec![]),
+        AuthorizationExpression::AccessToken(token) => return Some(vec![Term::from([token.clone()])]),
+        AuthorizationExpression::DisjunctionOf(nodes) | AuthorizationExpression::ConjunctionOf(nodes) => nodes,
+    };
+    let is_union = match expr {
+        AuthorizationExpression::DisjunctionOf(_) => disjunctive,
+        _ => !disjunctive,
+    };
+    if is_union {
+        let mut sets = Vec::new();
+        for node in nodes {
+            sets.extend(minimal_normal_form(node, disjunctive, limit)?);
+            if exceeds_limit(sets.len()) {
+                return None;
+            }
+        }
+        return Some(absorb(sets));
+    }
+    let mut sets = vec![Term::new()];
+    for node in nodes {
+        let rhs = minimal_normal_form(node, disjunctive, limit)?;
+        if exceeds_limit(sets.len().saturating_mul(rhs.len())) {
+            return None;
+        }
+        let mut product = Vec::with_capacity(sets.len() * rhs.len());
+        for a in &sets {
+            for b in &rhs {
+                product.push(a.union(b).cloned().collect());
+            }
+        }
+        sets = absorb(product);
+    }
+    Some(sets)
+}
+
+/// Removes duplicate terms and terms that are supersets of other terms (absorption: `A|(A&B)` = `A`).
+pub(crate) fn absorb(mut terms: Vec<Term>) -> Vec<Term> {
+    terms.sort_by(|a, b| a.len().cmp(&b.len()).then_with(|| a.cmp(b)));
+    terms.dedup();
+    let mut minimal: Vec<Term> = Vec::with_capacity(terms.len());
+    for term in terms {
+        if !minimal.iter().any(|m| m.is_subset(&term)) {
+            minimal.push(term);
+        }
+    }
+    minimal.sort();
+    minimal
+}
+
+/// Converts between DNF and CNF of a monotone function by computing the minimal transversals of
+/// the given sets: the terms of a DNF become the clauses of the equivalent CNF and vice versa.
+///
+/// Returns `None` if more than `limit` intermediate sets are produced.
+pub(crate) fn dualize(sets: &[Term], limit: Option<usize>) -> Option<Vec<Term>> {
+    let mut result = vec![Term::new()];
+    for set in sets {
+        let mut product = Vec::with_capacity(result.len() * set.len());
+        for partial in &result {
+            if !partial.is_disjoint(set) {
+                product.push(partial.clone());
+                continue;
+            }
+            for label in set {
+                let mut extended = partial.clone();
+                extended.insert(label.clone());
+                product.push(extended);
+            }
+        }
+        if limit.is_some_and(|limit| product.len() > limit) {
+            return None;
+        }
+        result = absorb(product);
+    }
+    Some(result)
+}
+
+/// Finds a term of the DNF `lhs` for which the expression `rhs` denies access, i.e. an authorization
+/// set for which `lhs` grants access while `rhs` denies it. As expressions are monotone, there is
+/// none iff `lhs` implies `rhs`, so the DNF of `rhs` isn't needed.
+pub(crate) fn find_denied_term<'a>(lhs: &'a [Term], rhs: &AuthorizationExpression) -> Option<&'a Term> {
+    lhs.iter().find(|term| {
+        let authorizations: HashSet<String> = term.iter().cloned().collect();
+        !rhs.evaluate_node(&authorizations)
+    })
+}
+
+pub(crate) fn term_to_authorizations(term: &Term) -> Authorizations {
+    let tokens: Vec<String> = term.iter().cloned().collect();
+    Authorizations::of(&tokens)
+}
+
+impl AuthorizationExpression {
+    /// Checks whether two expressions are semantically equivalent, i.e. whether they grant access
+    /// to exactly the same authorization sets.
+    ///
+    /// Unlike `==`, which compares the tree structure, this decides Boolean equivalence.
+    /// Use [`AuthorizationExpression::equivalence_counterexample`] to find out why two expressions differ.
+    ///
+    /// The check expands both expressions into their disjunctive normal forms, which can grow
+    /// exponentially (e.g. for a conjunction of many disjunctions), so it fails with
+    /// [`NormalFormError::LimitExceeded`] if either has more than [`ANALYSIS_DNF_LIMIT`] terms.
+    /// The `BddManager` of the `bdd` feature handles such expressions.
+    ///
+    /// # Example
+    /// ```
+    /// use accumulo_access::{Lexer, Parser};
+    /// let a = Parser::new(Lexer::new("A&(B|C)")).parse().unwrap();
+    /// let b = Parser::new(Lexer::new("(A&B)|(A&C)")).parse().unwrap();
+    /// assert_ne!(a, b);
+    /// assert_eq!(a.is_equivalent(&b), Ok(true));
+    /// ```
+    pub fn is_equivalent(&self, other: &AuthorizationExpression) -> Result<bool, NormalFormError> {
+        Ok(self.equivalence_counterexample(other)?.is_none())
+    }
+
+    /// Returns an authorization set for which the two expressions evaluate differently,
+    /// or `None` if they are equivalent. Fails like [`AuthorizationExpression::is_equivalent`].
+    ///
+    /// # Example
+    /// ```
+    /// use accumulo_access::{Lexer, Parser};
+    /// let a = Parser::new(Lexer::new("A&(B|C)")).parse().unwrap();
+    /// let b = Parser::new(Lexer::new("A&B")).parse().unwrap();
+    /// let counterexample = a.equivalence_counterexample(&b).unwrap().unwrap();
+    /// let auths = counterexample.to_set();
+    /// assert_ne!(a.evaluate(&auths), b.evaluate(&auths));
+    /// ```
+    pub fn equivalence_counterexample(&self, other: &AuthorizationExpression) -> Result<Option<Authorizations>, NormalFormError> {
+        let lhs = minimal_dnf(self)?;
+        let rhs = minimal_dnf(other)?;
+        Ok(find_denied_term(&lhs, other)
+            .or_else(|| find_denied_term(&rhs, self))
+            .map(term_to_authorizations))
+    }
+
+    /// Checks whether this expression implies `other`, i.e. whether every authorization set that
+    /// grants access to `self` also grants access to `other`.
+    ///
+    /// Each term of this expression's disjunctive normal form is evaluated against `other`, so it
+    /// fails with [`NormalFormError::LimitExceeded`] if that has more than [`ANALYSIS_DNF_LIMIT`]
+    /// terms, like [`AuthorizationExpression::is_equivalent`].
+    ///
+    /// # Example
+    /// ```
+    /// use accumulo_access::{Lexer, Parser};
+    /// let x = Parser::new(Lexer::new("A&B")).parse().unwrap();
+    /// let y = Parser::new(Lexer::new("A|C")).parse().unwrap();
+    /// assert_eq!(x.implies(&y), Ok(true));
+    /// assert_eq!(y.implies(&x), Ok(false));
+    /// ```
+    pub fn implies(&self, other: &AuthorizationExpression) -> Result<bool, NormalFormError> {
+        Ok(self.implication_counterexample(other)?.is_none())
+    }
+
+    /// Returns an authorization set that grants access to `self` but not to `other`,
+    /// or `None` if `self` implies `other`. Fails like [`AuthorizationExpression::implies`].
+    ///
+    /// # Example
+    /// ```
+    /// use accumulo_access::{Lexer, Parser};
+    /// let x = Parser::new(Lexer::new("A|C")).parse().unwrap();
+    /// let y = Parser::new(Lexer::new("A")).parse().unwrap();
+    /// let witness = x.implication_counterexample(&y).unwrap().unwrap().to_set();
+    /// assert!(x.evaluate(&witness));
+    /// assert!(!y.evaluate(&witness));
+    /// ```
+    pub fn implication_counterexample(&self, other: &AuthorizationExpression) -> Result<Option<Authorizations>, NormalFormError> {
+        let lhs = minimal_dnf(self)?;
+        Ok(find_denied_term(&lhs, other).map(term_to_authorizations))
+    }
+
+    /// Checks whether this expression is strictly more restrictive than `other`, i.e. it implies
+    /// `other` but is not equivalent to it. Fails like [`AuthorizationExpression::is_equivalent`].
+    ///
+    /// # Example
+    /// ```
+    /// use accumulo_access::{Lexer, Parser};
+    /// let x = Parser::new(Lexer::new("A&B")).parse().unwrap();
+    /// let y = Parser::new(Lexer::new("A")).parse().unwrap();
+    /// assert_eq!(x.strictly_more_restrictive(&y), Ok(true));
+    /// assert_eq!(y.strictly_more_restrictive(&x), Ok(false));
+    /// assert_eq!(x.strictly_more_restrictive(&x), Ok(false));
+    /// ```
+    pub fn strictly_more_restrictive(&self, other: &AuthorizationExpression) -> Result<bool, NormalFormError> {
+        Ok(self.implies(other)? && !other.implies(self)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Lexer, Parser};
+    use rstest::rstest;
+
+    fn parse(expression: &str) -> AuthorizationExpression {
+        Parser::new(Lexer::new(expression)).parse().unwrap()
+    }
+
+    #[rstest]
+    #[case("A&(B|C)", "(A&B)|(A&C)", true)]
+    #[case("A|(B&C)", "(A|B)&(A|C)", true)]
+    #[case("(A&B)|(A&C)|(A&B&D)", "A&(B|C)", true)]
+    #[case("A|(A&B)", "A", true)]
+    #[case("A&(A|B)", "A", true)]
+    #[case("B&A", "A&B", true)]
+    #[case("", "", true)]
+    #[case("A&(B|C)", "A&B", false)]
+    #[case("A|B", "A&B", false)]
+    #[case("A", "B", false)]
+    #[case("", "A", false)]
+    fn test_is_equivalent(#[case] a: &str, #[case] b: &str, #[case] expected: bool) {
+        let a = parse(a);
+        let b = parse(b);
+        assert_eq!(a.is_equivalent(&b), Ok(expected));
+        assert_eq!(b.is_equivalent(&a), Ok(expected));
+
+        match a.equivalence_counterexample(&b).unwrap() {
+            Some(counterexample) => {
+                let auths = counterexample.to_set();
+                assert_ne!(a.evaluate(&auths), b.evaluate(&auths));
+            }
+            None => assert!(expected),
+        }
+    }
+
+    #[rstest]
+    #[case("A&B", "A", true)]
+    #[case("A&B", "A|C", true)]
+    #[case("A&(B|C)", "(A&B)|(A&C)", true)]
+    #[case("A", "", true)]
+    #[case("A", "A&B", false)]
+    #[case("A|C", "A", false)]
+    #[case("", "A", false)]
+    fn test_implies(#[case] x: &str, #[case] y: &str, #[case] expected: bool) {
+        let x = parse(x);
+        let y = parse(y);
+        assert_eq!(x.implies(&y), Ok(expected));
+
+        match x.implication_counterexample(&y).unwrap() {
+            Some(witness) => {
+                let auths = witness.to_set();
+                assert!(x.evaluate(&auths));
+                assert!(!y.evaluate(&auths));
+            }
+            None => assert!(expected),
+        }
+    }
+
+    #[rstest]
+    #[case("A&B", "A", true)]
+    #[case("A", "A|B", true)]
+    #[case("A&(B|C)", "(A&B)|(A&C)", false)]
+    #[case("A", "B", false)]
+    #[case("A|B", "A", false)]
+    fn test_strictly_more_restrictive(#[case] x: &str, #[case] y: &str, #[case] expected: bool) {
+        assert_eq!(parse(x).strictly_more_restrictive(&parse(y)), Ok(expected));
+    }
+
+    #[test]
+    fn minimal_dnf_applies_absorption() {
+        let dnf = minimal_dnf(&parse("(A&B)|(A&C)|(A&B&D)|(A&B)")).unwrap();
+        assert_eq!(dnf, vec![
+            Term::from(["A".to_string(), "B".to_string()]),
+            Term::from(["A".to_string(), "C".to_string()]),
+        ]);
+    }
+
+    #[test]
+    fn dualize_converts_between_dnf_and_cnf() {
+        let dnf = minimal_dnf(&parse("(A&C)|(A&D)|(B&C)|(B&D)")).unwrap();
+        let cnf = dualize(&dnf, None).unwrap();
+        assert_eq!(cnf, vec![
+            Term::from(["A".to_string(), "B".to_string()]),
+            Term::from(["C".to_string(), "D".to_string()]),
+        ]);
+        assert_eq!(dualize(&cnf, None).unwrap(), dnf);
+        assert_eq!(dualize(&dnf, Some(2)), None);
+    }
+
+    #[test]
+    fn empty_disjunction_is_never_satisfied() {
+        let never = AuthorizationExpression::DisjunctionOf(vec![]);
+        let always = AuthorizationExpression::ConjunctionOf(vec![]);
+        assert_eq!(always.is_equivalent(&AuthorizationExpression::Nil), Ok(true));
+        assert_eq!(never.is_equivalent(&AuthorizationExpression::Nil), Ok(false));
+        assert!(never.equivalence_counterexample(&always).unwrap().unwrap().to_set().is_empty());
+    }
+
+    #[test]
+    fn equivalence_of_large_products_exceeds_the_limit() {
+        // (A0|B0)&(A1|B1)&...: the DNF doubles with every disjunction.
+        let expression = (0..20).map(|i| format!("(A{i}|B{i})")).collect::<Vec<_>>().join("&");
+        let expr = parse(&expression);
+        assert_eq!(expr.is_equivalent(&expr), Err(NormalFormError::LimitExceeded(ANALYSIS_DNF_LIMIT)));
+        assert_eq!(expr.equivalence_counterexample(&parse("A0")), Err(NormalFormError::LimitExceeded(ANALYSIS_DNF_LIMIT)));
+    }
+
+    #[test]
+    fn implication_expands_only_the_implying_expression() {
+        let expression = (0..20).map(|i| format!("(A{i}|B{i})")).collect::<Vec<_>>().join("&");
+        let expr = parse(&expression);
+        let a = parse("A0&A1&A2&A3&A4&A5&A6&A7&A8&A9&A10&A11&A12&A13&A14&A15&A16&A17&A18&A19");
+        assert_eq!(a.implies(&expr), Ok(true));
+        assert_eq!(expr.implies(&a), Err(NormalFormError::LimitExceeded(ANALYSIS_DNF_LIMIT)));
+        assert_eq!(expr.implication_counterexample(&a), Err(NormalFormError::LimitExceeded(ANALYSIS_DNF_LIMIT)));
+        assert_eq!(a.strictly_more_restrictive(&expr), Err(NormalFormError::LimitExceeded(ANALYSIS_DNF_LIMIT)));
+    }
+}
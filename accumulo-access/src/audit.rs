@@ -0,0 +1,428 @@
+// Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
+// Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE_MIT or LICENSE_APACHE files.
+
+//! An audit trail of authorization decisions.
+//!
+//! Every decision made by an [`AccessEvaluator`](crate::AccessEvaluator) with an audit sink, or by
+//! [`check_authorization`](crate::check_authorization) and the functions of the `caching` module
+//! once a process-wide sink is installed with [`set_audit_sink`], is passed to the sink as an
+//! [`AuditRecord`].
+
+use std::collections::{BTreeSet, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::hashing::Fnv1a;
+use crate::{AuthorizationExpression, ParserError};
+
+/// The outcome of an audited authorization check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditDecision {
+    Granted,
+    Denied,
+    /// The expression could not be parsed, so access was denied.
+    Invalid,
+}
+
+/// One audited authorization decision.
+///
+/// # Example
+/// ```
+/// use std::sync::Arc;
+/// use accumulo_access::{AccessEvaluator, Authorizations};
+/// use accumulo_access::audit::{AuditDecision, MemoryAuditSink};
+///
+/// let sink = Arc::new(MemoryAuditSink::new());
+/// let evaluator = AccessEvaluator::of(&Authorizations::of(&["A".to_string(), "C".to_string()]))
+///     .with_audit_sink(sink.clone(), "alice");
+/// assert_eq!(evaluator.can_access("A&(B|C)"), Ok(true));
+///
+/// let record = &sink.records()[0];
+/// assert_eq!(record.principal.as_deref(), Some("alice"));
+/// assert_eq!(record.decision, AuditDecision::Granted);
+/// assert_eq!(record.decisive_labels, ["A", "C"]);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// When the decision was made, in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    /// The principal whose authorizations were checked, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub principal: Option<String>,
+    pub expression: String,
+    /// The hash of the authorization set, see [`authorization_set_hash`].
+    pub authorizations_hash: String,
+    pub decision: AuditDecision,
+    /// The labels the decision depended on: for a grant, the held labels that satisfied the
+    /// expression; for a denial, the missing labels that made it fail. Sorted and deduplicated.
+    pub decisive_labels: Vec<String>,
+    /// The parser error, if the decision is [`AuditDecision::Invalid`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AuditRecord {
+    pub(crate) fn new(
+        principal: Option<&str>,
+        expression: &str,
+        authorizations: &HashSet<String>,
+        outcome: Result<(&AuthorizationExpression, bool), &ParserError>,
+    ) -> Self {
+        let (decision, decisive_labels, error) = match outcome {
+            Ok((expr, granted)) => {
+                let mut labels = BTreeSet::new();
+                collect_decisive_labels(expr, authorizations, granted, &mut labels);
+                let decision = if granted { AuditDecision::Granted } else { AuditDecision::Denied };
+                (decision, labels.into_iter().map(str::to_string).collect(), None)
+            }
+            Err(error) => (AuditDecision::Invalid, Vec::new(), Some(error.to_string())),
+        };
+        Self::with_outcome(principal, expression, authorizations, decision, decisive_labels, error)
+    }
+
+    /// The record of a decision reused from a decision cache, with the decisive labels collected
+    /// when the decision was made.
+    #[cfg(feature = "caching")]
+    pub(crate) fn cached(
+        expression: &str,
+        authorizations: &HashSet<String>,
+        decision: &Result<bool, ParserError>,
+        decisive_labels: &[String],
+    ) -> Self {
+        let (decision, error) = match decision {
+            Ok(true) => (AuditDecision::Granted, None),
+            Ok(false) => (AuditDecision::Denied, None),
+            Err(error) => (AuditDecision::Invalid, Some(error.to_string())),
+        };
+        Self::with_outcome(None, expression, authorizations, decision, decisive_labels.to_vec(), error)
+    }
+
+    fn with_outcome(
+        principal: Option<&str>,
+        expression: &str,
+        authorizations: &HashSet<String>,
+        decision: AuditDecision,
+        decisive_labels: Vec<String>,
+        error: Option<String>,
+    ) -> Self {
+        AuditRecord {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX)),
+            principal: principal.map(str::to_string),
+            expression: expression.to_string(),
+            authorizations_hash: authorization_set_hash(authorizations),
+            decision,
+            decisive_labels,
+            error,
+        }
+    }
+}
+
+/// Collects the labels that explain why `expr` evaluates to `granted`, following the same
+/// short-circuiting as the evaluation: the first satisfied operand of a granted disjunction and the
+/// first failing operand of a denied conjunction.
+fn collect_decisive_labels<'a>(expr: &'a AuthorizationExpression, authorizations: &HashSet<String>, granted: bool, labels: &mut BTreeSet<&'a str>) {
+    match expr {
+        AuthorizationExpression::AccessToken(token) => {
+            labels.insert(token);
+        }
+        AuthorizationExpression::ConjunctionOf(nodes) | AuthorizationExpression::DisjunctionOf(nodes) => {
+            let any = matches!(expr, AuthorizationExpression::DisjunctionOf(_));
+            if granted == any {
+                // One operand with the same outcome decided it.
+                if let Some(node) = nodes.iter().find(|node| node.evaluate_node(authorizations) == granted) {
+                    collect_decisive_labels(node, authorizations, granted, labels);
+                }
+            } else {
+                for node in nodes {
+                    collect_decisive_labels(node, authorizations, granted, labels);
+                }
+            }
+        }
+        AuthorizationExpression::Nil => {}
+    }
+}
+
+/// A stable hash of a set of access tokens, independent of their order and of duplicates, as
+/// recorded in [`AuditRecord::authorizations_hash`].
+///
+/// # Example
+/// ```
+/// use accumulo_access::audit::authorization_set_hash;
+///
+/// assert_eq!(authorization_set_hash(["A", "B"]), authorization_set_hash(["B", "A", "B"]));
+/// assert_ne!(authorization_set_hash(["A", "B"]), authorization_set_hash(["AB"]));
+/// ```
+pub fn authorization_set_hash<I, S>(tokens: I) -> String
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let tokens: Vec<S> = tokens.into_iter().collect();
+    let mut hasher = Fnv1a::with_salt(0);
+    for token in tokens.iter().map(AsRef::as_ref).collect::<BTreeSet<&str>>() {
+        hasher.write(&(token.len() as u64).to_le_bytes());
+        hasher.write(token.as_bytes());
+    }
+    format!("{:016x}", hasher.finish())
+}
+
+/// Receives a record of every audited authorization decision.
+///
+/// Sinks are called synchronously on the thread making the decision, so they should be fast or
+/// hand the records off to a background writer.
+pub trait AuditSink: Send + Sync {
+    fn record(&self, record: &AuditRecord);
+}
+
+static AUDIT_SINK: OnceLock<Arc<dyn AuditSink>> = OnceLock::new();
+
+/// Installs the sink of the decisions made by [`check_authorization`](crate::check_authorization),
+/// the `caching` module and every [`AccessEvaluator`](crate::AccessEvaluator) without a sink of its own.
+///
+/// The sink can only be installed once; later calls hand the given sink back as an error.
+pub fn set_audit_sink(sink: Arc<dyn AuditSink>) -> Result<(), Arc<dyn AuditSink>> {
+    AUDIT_SINK.set(sink)
+}
+
+pub(crate) fn audit_sink() -> Option<&'static dyn AuditSink> {
+    AUDIT_SINK.get().map(Arc::as_ref)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Keeps the records in memory, e.g. for tests or for shipping them in batches.
+#[derive(Debug, Default)]
+pub struct MemoryAuditSink {
+    records: Mutex<Vec<AuditRecord>>,
+}
+
+impl MemoryAuditSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A copy of the records received so far.
+    pub fn records(&self) -> Vec<AuditRecord> {
+        lock(&self.records).clone()
+    }
+
+    /// Removes and returns the records received so far.
+    pub fn take(&self) -> Vec<AuditRecord> {
+        std::mem::take(&mut *lock(&self.records))
+    }
+
+    pub fn len(&self) -> usize {
+        lock(&self.records).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        lock(&self.records).is_empty()
+    }
+}
+
+impl AuditSink for MemoryAuditSink {
+    fn record(&self, record: &AuditRecord) {
+        lock(&self.records).push(record.clone());
+    }
+}
+
+/// Writes every record as one line of JSON, flushing after each line.
+///
+/// Write errors don't affect the decisions; they are counted, see [`JsonLinesAuditSink::failures`].
+///
+/// # Example
+/// ```no_run
+/// use std::sync::Arc;
+/// use accumulo_access::audit::{self, JsonLinesAuditSink};
+///
+/// let sink = JsonLinesAuditSink::open("/var/log/accumulo-access/audit.jsonl").unwrap();
+/// audit::set_audit_sink(Arc::new(sink)).ok();
+/// ```
+pub struct JsonLinesAuditSink {
+    writer: Mutex<Box<dyn Write + Send>>,
+    failures: AtomicU64,
+}
+
+impl JsonLinesAuditSink {
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        JsonLinesAuditSink {
+            writer: Mutex::new(Box::new(writer)),
+            failures: AtomicU64::new(0),
+        }
+    }
+
+    /// Appends to the file at `path`, creating it if it doesn't exist.
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file: File = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(LineWriter::new(file)))
+    }
+
+    /// The number of records that could not be written.
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+}
+
+impl AuditSink for JsonLinesAuditSink {
+    fn record(&self, record: &AuditRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(_) => {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        line.push(b'\n');
+        let mut writer = lock(&self.writer);
+        if writer.write_all(&line).and_then(|_| writer.flush()).is_err() {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AccessEvaluator, Authorizations, Lexer, Parser};
+    use rstest::rstest;
+
+    fn labels(csv: &str) -> HashSet<String> {
+        csv.split(',').filter(|label| !label.is_empty()).map(str::to_string).collect()
+    }
+
+    #[rstest]
+    #[case("A&B", "A,B,C", true, &["A", "B"])]
+    #[case("A|B", "A", true, &["A"])]
+    #[case("A|B", "B", true, &["B"])]
+    #[case("A&B", "A", false, &["B"])]
+    #[case("A|B", "", false, &["A", "B"])]
+    #[case("A&(B|C)", "A,C", true, &["A", "C"])]
+    #[case("(A&B)|(C&D)", "A,D", false, &["B", "C"])]
+    #[case("(A&B)|(C&D)", "C,D", true, &["C", "D"])]
+    fn decisive_labels_explain_the_decision(#[case] expression: &str, #[case] authorizations: &str, #[case] granted: bool, #[case] expected: &[&str]) {
+        let expr = Parser::new(Lexer::new(expression)).parse().unwrap();
+        let authorizations = labels(authorizations);
+        assert_eq!(expr.evaluate(&authorizations), granted);
+
+        let record = AuditRecord::new(None, expression, &authorizations, Ok((&expr, granted)));
+        assert_eq!(record.decisive_labels, expected);
+    }
+
+    #[test]
+    fn evaluator_records_invalid_expressions() {
+        let sink = Arc::new(MemoryAuditSink::new());
+        let evaluator = AccessEvaluator::of(&Authorizations::of(&["A".to_string()])).with_audit_sink(sink.clone(), "bob");
+        assert!(evaluator.can_access("A&B|C").is_err());
+        let expr = Parser::new(Lexer::new("A&B")).parse().unwrap();
+        assert!(!evaluator.can_access_expression(&expr));
+
+        let records = sink.take();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].decision, AuditDecision::Invalid);
+        assert_eq!(records[0].error.as_deref(), Some("Mixing operators"));
+        assert_eq!(records[1].decision, AuditDecision::Denied);
+        assert_eq!(records[1].expression, expr.to_expression_str());
+        assert_eq!(records[1].authorizations_hash, authorization_set_hash(["A"]));
+        assert!(sink.is_empty());
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            lock(&self.0).write(bytes)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_lines_round_trip() {
+        let buffer = SharedBuffer::default();
+        let sink = Arc::new(JsonLinesAuditSink::new(buffer.clone()));
+        let evaluator = AccessEvaluator::of(&Authorizations::of(&["A".to_string()])).with_audit_sink(sink.clone(), "carol");
+        evaluator.can_access("A|B").unwrap();
+        evaluator.can_access("\"a b\"&A").unwrap();
+
+        let output = String::from_utf8(lock(&buffer.0).clone()).unwrap();
+        let records: Vec<AuditRecord> = output.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].decision, AuditDecision::Granted);
+        assert_eq!(records[1].expression, "\"a b\"&A");
+        assert_eq!(records[1].decisive_labels, ["a b"]);
+        assert!(!output.contains("\"error\""));
+        assert_eq!(sink.failures(), 0);
+    }
+
+    /// The process-wide sink, shared by the tests that need it; they filter its records by a unique expression.
+    fn global_sink() -> Arc<MemoryAuditSink> {
+        static SINK: OnceLock<Arc<MemoryAuditSink>> = OnceLock::new();
+        SINK.get_or_init(|| {
+            let sink = Arc::new(MemoryAuditSink::new());
+            assert!(set_audit_sink(sink.clone()).is_ok());
+            sink
+        })
+        .clone()
+    }
+
+    #[test]
+    fn global_sink_receives_free_function_decisions() {
+        let sink = global_sink();
+        assert!(set_audit_sink(sink.clone()).is_err());
+
+        let tokens = vec!["audited".to_string()];
+        assert_eq!(crate::check_authorization("audited|other", &tokens), Ok(true));
+        #[cfg(feature = "caching")]
+        {
+            assert_eq!(crate::caching::check_authorization_csv("audited&other".to_string(), "audited".to_string()), Ok(false));
+            assert_eq!(crate::caching::check_authorization_csv("audited&other".to_string(), "audited".to_string()), Ok(false));
+        }
+
+        let records: Vec<AuditRecord> = sink.records().into_iter().filter(|record| record.expression.starts_with("audited")).collect();
+        assert_eq!(records[0].decisive_labels, ["audited"]);
+        assert_eq!(records[0].principal, None);
+        #[cfg(feature = "caching")]
+        {
+            assert_eq!(records.len(), 3, "cache hits are audited too");
+            assert_eq!(records[2].decisive_labels, ["other"]);
+            assert_eq!(AuditRecord { timestamp_ms: records[1].timestamp_ms, ..records[2].clone() }, records[1]);
+        }
+    }
+
+    #[cfg(feature = "caching")]
+    #[test]
+    fn cache_hits_are_audited_with_the_labels_collected_on_the_miss() {
+        use crate::caching::{AuthorizationCache, AuthorizationSetKey, CachedDecision, DecisionCache, DecisionKey};
+
+        let sink = global_sink();
+        let cache = AuthorizationCache::new(10);
+        let tokens = vec!["cached".to_string()];
+        assert_eq!(cache.check_authorization("cached|other", &tokens), Ok(true));
+        let key = DecisionKey::new("cached|other", AuthorizationSetKey::new(&tokens));
+        assert_eq!(cache.get(&key).unwrap().decisive_labels.as_deref(), Some(&["cached".to_string()][..]));
+
+        // A decision cached before the sink was installed has no labels; they are derived once more.
+        let key = DecisionKey::new("cached&other", AuthorizationSetKey::new(&tokens));
+        cache.insert(key, CachedDecision { decision: Ok(false), decisive_labels: None });
+        assert_eq!(cache.check_authorization("cached&other", &tokens), Ok(false));
+
+        let records: Vec<AuditRecord> = sink.records().into_iter().filter(|record| record.expression.starts_with("cached")).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].decision, AuditDecision::Denied);
+        assert_eq!(records[1].decisive_labels, ["other"]);
+    }
+}
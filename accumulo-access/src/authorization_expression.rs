@@ -2,6 +2,12 @@ use std::cmp::Ordering;
 use std::collections::HashSet;
 use std::fmt::{Display, Formatter};
 use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+use crate::binary::{DecodeError, EncodedExpression};
+use crate::error::{AccessError, JsonError};
+use crate::lexer::quote;
+use crate::{Lexer, Parser, ParserError};
 
 #[derive(Debug, Clone)]
 pub enum AuthorizationExpression {
@@ -93,8 +99,30 @@ impl Display for AuthorizationExpression {
     }
 }
 
+/// Parses an expression, e.g. `"A&(B|C)".parse::<AuthorizationExpression>()`.
+impl FromStr for AuthorizationExpression {
+    type Err = ParserError;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        Parser::new(Lexer::new(expression)).parse()
+    }
+}
+
+impl TryFrom<&str> for AuthorizationExpression {
+    type Error = ParserError;
+
+    fn try_from(expression: &str) -> Result<Self, Self::Error> {
+        expression.parse()
+    }
+}
+
 impl AuthorizationExpression {
     /// Create a new `AuthorizationExpression` from a JSON value.
+    ///
+    /// The value must be a tree as produced by [`AuthorizationExpression::to_json`]: a label (non-empty
+    /// string), an object with exactly one `"and"` or `"or"` key holding a non-empty array of nodes,
+    /// or `null` for the empty expression. Anything else fails with a [`JsonError::InvalidTree`]
+    /// pointing at the offending node.
     /// 
     /// # Arguments
     /// json - The JSON value to parse.
@@ -118,30 +146,93 @@ impl AuthorizationExpression {
     /// });
     /// let expr = AuthorizationExpression::from_json(&json).unwrap();
     /// ```
-    pub fn from_json(json: &serde_json::Value) -> Result<Self, String> {
-        match json {
-            serde_json::Value::Object(obj) => {
-                if obj.contains_key("and") {
-                    let and = obj.get("and").unwrap().as_array().unwrap();
-                    let mut nodes = Vec::new();
-                    for node in and {
-                        nodes.push(AuthorizationExpression::from_json(node)?);
-                    }
-                    Ok(AuthorizationExpression::ConjunctionOf(nodes))
-                } else if obj.contains_key("or") {
-                    let or = obj.get("or").unwrap().as_array().unwrap();
-                    let mut nodes = Vec::new();
-                    for node in or {
-                        nodes.push(AuthorizationExpression::from_json(node)?);
+    pub fn from_json(json: &serde_json::Value) -> Result<Self, AccessError> {
+        Ok(crate::json::read_tree(json)?)
+    }
+
+    /// Create a new `AuthorizationExpression` from the JSON text of a tree, as produced by [`AuthorizationExpression::to_json_str`].
+    ///
+    /// # Example
+    /// ```
+    /// use accumulo_access::{AccessError, AuthorizationExpression, JsonError};
+    /// let expr = AuthorizationExpression::from_json_str(r#"{"or": ["A", "B"]}"#).unwrap();
+    /// assert_eq!(expr.to_expression_str(), "A|B");
+    ///
+    /// match AuthorizationExpression::from_json_str(r#"{"or": ["A", {"and": "B"}]}"#) {
+    ///     Err(AccessError::Json(JsonError::InvalidTree { pointer, .. })) => assert_eq!(pointer, "/or/1/and"),
+    ///     other => panic!("unexpected {:?}", other),
+    /// }
+    /// ```
+    pub fn from_json_str(json: &str) -> Result<Self, AccessError> {
+        let json: serde_json::Value = serde_json::from_str(json).map_err(|e| JsonError::ParsingFailed(e.to_string()))?;
+        Self::from_json(&json)
+    }
+
+    /// The JSON Schema (draft 2020-12) of the tree format read by [`AuthorizationExpression::from_json`] and
+    /// written by [`AuthorizationExpression::to_json`], for validating trees in other languages.
+    ///
+    /// # Example
+    /// ```
+    /// use accumulo_access::AuthorizationExpression;
+    /// let schema = AuthorizationExpression::json_schema();
+    /// assert_eq!(schema["$defs"]["label"]["minLength"], 1);
+    /// ```
+    pub fn json_schema() -> serde_json::Value {
+        serde_json::from_str(crate::json::SCHEMA).expect("the bundled schema is valid JSON")
+    }
+
+    /// Encodes the expression in the compact binary format described in [`BINARY_FORMAT_VERSION`](crate::BINARY_FORMAT_VERSION).
+    ///
+    /// The tree is validated like the trees read by [`AuthorizationExpression::from_json`], as the
+    /// format can't hold empty labels, empty scopes or a nested empty expression; so every encoding
+    /// can be decoded.
+    ///
+    /// # Example
+    /// ```
+    /// use accumulo_access::{AuthorizationExpression, Lexer, Parser};
+    /// let expr = Parser::new(Lexer::new("A&(B|C)")).parse().unwrap();
+    /// assert_eq!(AuthorizationExpression::decode(&expr.encode().unwrap()), Ok(expr));
+    /// assert!(AuthorizationExpression::DisjunctionOf(vec![]).encode().is_err());
+    /// ```
+    pub fn encode(&self) -> Result<Vec<u8>, JsonError> {
+        crate::json::validate_tree(self)?;
+        let mut encoder = crate::binary::Encoder::default();
+        encoder.expression(self);
+        Ok(encoder.finish())
+    }
+
+    /// Decodes an expression written by [`AuthorizationExpression::encode`]. Use
+    /// [`EncodedExpression`] to evaluate it without building the tree.
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        Ok(EncodedExpression::new(bytes)?.to_expression())
+    }
+
+    /// Iterates over the labels of the expression, depth-first in the order of the tree, reporting labels
+    /// referenced more than once every time.
+    ///
+    /// # Example
+    /// ```
+    /// use std::collections::BTreeSet;
+    /// use accumulo_access::AuthorizationExpression;
+    /// let expr: AuthorizationExpression = "A&(B|\"c d\"|A)".parse().unwrap();
+    /// let labels: BTreeSet<&str> = expr.labels().collect();
+    /// assert_eq!(labels, BTreeSet::from(["A", "B", "c d"]));
+    /// assert_eq!(expr.labels().count(), 4);
+    /// ```
+    pub fn labels(&self) -> impl Iterator<Item = &str> + '_ {
+        let mut stack = vec![self];
+        std::iter::from_fn(move || {
+            while let Some(node) = stack.pop() {
+                match node {
+                    AuthorizationExpression::AccessToken(token) => return Some(token.as_str()),
+                    AuthorizationExpression::ConjunctionOf(nodes) | AuthorizationExpression::DisjunctionOf(nodes) => {
+                        stack.extend(nodes.iter().rev());
                     }
-                    Ok(AuthorizationExpression::DisjunctionOf(nodes))
-                } else {
-                    Err("Invalid JSON object".to_string())
+                    AuthorizationExpression::Nil => {}
                 }
             }
-            serde_json::Value::String(token) => Ok(AuthorizationExpression::AccessToken(token.to_string())),
-            _ => Err("Invalid JSON value".to_string()),
-        }
+            None
+        })
     }
 
     /// Evaluate the expression with the given set of authorizations.
@@ -168,14 +259,44 @@ impl AuthorizationExpression {
     /// assert_eq!(expr.evaluate(&authorizations), true);
     /// ```
     pub fn evaluate(&self, authorizations: &HashSet<String>) -> bool {
+        self.evaluate_parsed_from(None, authorizations)
+    }
+
+    /// Evaluates the expression, fingerprinting its span by the text it was parsed from (if any)
+    /// so that it correlates with the parse and cache spans of that text.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub(crate) fn evaluate_parsed_from(&self, text: Option<&str>, authorizations: &HashSet<String>) -> bool {
+        #[cfg(feature = "tracing")]
+        let span = tracing::trace_span!(
+            "evaluate",
+            expression.fingerprint = %match text {
+                Some(text) => crate::trace::fingerprint(text),
+                None => crate::trace::fingerprint(&self.to_expression_str()),
+            },
+            expression = %crate::trace::expression(self),
+            label_count = crate::trace::label_count(self),
+            authorization_count = authorizations.len(),
+            decision = tracing::field::Empty,
+        );
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+        let granted = self.evaluate_node(authorizations);
+        #[cfg(feature = "metrics")]
+        crate::telemetry::record_evaluation(granted);
+        #[cfg(feature = "tracing")]
+        span.record("decision", granted);
+        granted
+    }
+
+    pub(crate) fn evaluate_node(&self, authorizations: &HashSet<String>) -> bool {
         match self {
             AuthorizationExpression::Nil => true,
 
             AuthorizationExpression::ConjunctionOf(nodes) =>
-                nodes.iter().all(|node| node.evaluate(authorizations)),
+                nodes.iter().all(|node| node.evaluate_node(authorizations)),
 
             AuthorizationExpression::DisjunctionOf(nodes) =>
-                nodes.iter().any(|node| node.evaluate(authorizations)),
+                nodes.iter().any(|node| node.evaluate_node(authorizations)),
 
             AuthorizationExpression::AccessToken(token) => authorizations.contains(token),
         }
@@ -261,30 +382,42 @@ impl AuthorizationExpression {
     ///
     /// let expr_str = expr2.to_expression_str();
     /// assert_eq!(expr_str, "A|B");
+    ///
+    /// let expr3 = AuthorizationExpression::ConjunctionOf(vec![
+    /// AuthorizationExpression::AccessToken("A".to_string()),
+    /// AuthorizationExpression::DisjunctionOf(vec![
+    /// AuthorizationExpression::AccessToken("B".to_string()),
+    /// AuthorizationExpression::AccessToken("label 🕺".to_string()),
+    /// ]),
+    /// ]);
+    ///
+    /// let expr_str = expr3.to_expression_str();
+    /// assert_eq!(expr_str, "A&(B|\"label 🕺\")");
     pub fn to_expression_str(&self) -> String {
         // serialize the expression tree back as a valid Accumulo Security Expression including parentheses, optional quotes, '&' and '|'.
         match self {
             AuthorizationExpression::Nil => String::new(),
-            AuthorizationExpression::ConjunctionOf(nodes) => {
-                let mut expression = String::new();
-                for node in nodes {
-                    expression.push_str(&node.to_expression_str());
-                    expression.push('&');
-                }
-                expression.pop();
-                expression
-            }
-            AuthorizationExpression::DisjunctionOf(nodes) => {
-                let mut expression = String::new();
-                for node in nodes {
+            AuthorizationExpression::ConjunctionOf(nodes) => Self::join_nodes(nodes, '&'),
+            AuthorizationExpression::DisjunctionOf(nodes) => Self::join_nodes(nodes, '|'),
+            AuthorizationExpression::AccessToken(token) => quote(token).into_owned(),
+        }
+    }
+
+    fn join_nodes(nodes: &[AuthorizationExpression], operator: char) -> String {
+        let mut expression = String::new();
+        for node in nodes {
+            match node {
+                AuthorizationExpression::ConjunctionOf(_) | AuthorizationExpression::DisjunctionOf(_) => {
+                    expression.push('(');
                     expression.push_str(&node.to_expression_str());
-                    expression.push('|');
+                    expression.push(')');
                 }
-                expression.pop();
-                expression
+                _ => expression.push_str(&node.to_expression_str()),
             }
-            AuthorizationExpression::AccessToken(token) => token.clone(),
+            expression.push(operator);
         }
+        expression.pop();
+        expression
     }
 
     /// Normalize the expression tree by sorting and deduplicating the nodes.
@@ -361,6 +494,16 @@ mod tests {
         ]));
     }
 
+    #[test]
+    fn from_str_parses() {
+        let expr: AuthorizationExpression = "A&B".parse().unwrap();
+        assert_eq!(expr, AuthorizationExpression::ConjunctionOf(vec![
+            AuthorizationExpression::AccessToken("A".to_string()),
+            AuthorizationExpression::AccessToken("B".to_string()),
+        ]));
+        assert_eq!(AuthorizationExpression::try_from("A&B|C"), Err(ParserError::MixingOperators));
+    }
+
     #[test]
     fn new_expr_from_json() {
         let json = serde_json::json!({
@@ -384,6 +527,14 @@ mod tests {
         ]));
     }
 
+    #[test]
+    fn to_expression_str_round_trips_through_parser() {
+        let expression = "A&(\"b c\"|(D&\"e\\\"f\"))&\"g\\\\h\"";
+        let expr = crate::Parser::new(crate::Lexer::new(expression)).parse().unwrap();
+        let reparsed = crate::Parser::new(crate::Lexer::new(&expr.to_expression_str())).parse().unwrap();
+        assert_eq!(expr, reparsed);
+    }
+
     #[test]
     fn test_normalize1() {
         let mut expr = AuthorizationExpression::ConjunctionOf(vec![
@@ -35,6 +35,10 @@ impl Authorizations {
         self.auths.contains(auth)
     }
     
+    pub fn iter(&self) -> impl Iterator<Item = &String> {
+        self.auths.iter()
+    }
+
     pub fn to_set(&self) -> HashSet<String> {
         self.auths.clone()
     }
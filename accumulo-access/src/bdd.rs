@@ -0,0 +1,424 @@
+// Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
+// Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE_MIT or LICENSE_APACHE files.
+
+//! Reduced ordered binary decision diagrams (BDDs) for analysing authorization expressions.
+//!
+//! All BDDs built by one [`BddManager`] share their nodes, so two expressions are equivalent
+//! iff they compile to the same [`Bdd`] handle.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::authorization_expression::AuthorizationExpression;
+use crate::authorizations::Authorizations;
+
+/// A handle to a BDD node owned by a [`BddManager`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Bdd(u32);
+
+impl Bdd {
+    /// The constant `false` function.
+    pub const FALSE: Bdd = Bdd(0);
+    /// The constant `true` function.
+    pub const TRUE: Bdd = Bdd(1);
+
+    pub fn is_terminal(self) -> bool {
+        self.0 <= 1
+    }
+}
+
+/// A binary operation on BDDs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BddOp {
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Node {
+    level: u32,
+    low: Bdd,
+    high: Bdd,
+}
+
+const TERMINAL_LEVEL: u32 = u32::MAX;
+
+/// `BddManager` owns the nodes of all BDDs built with it, and the variable order.
+///
+/// Each access token is a variable. Variables that are not part of the initial order are
+/// appended below all existing variables when first seen.
+#[derive(Debug, Clone)]
+pub struct BddManager {
+    labels: Vec<String>,
+    levels: HashMap<String, u32>,
+    nodes: Vec<Node>,
+    unique: HashMap<Node, Bdd>,
+    computed: HashMap<(BddOp, Bdd, Bdd), Bdd>,
+}
+
+impl Default for BddManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BddManager {
+    /// Creates a new `BddManager` with no variables.
+    pub fn new() -> Self {
+        let terminal = |value| Node { level: TERMINAL_LEVEL, low: Bdd(value), high: Bdd(value) };
+        BddManager {
+            labels: Vec::new(),
+            levels: HashMap::new(),
+            nodes: vec![terminal(0), terminal(1)],
+            unique: HashMap::new(),
+            computed: HashMap::new(),
+        }
+    }
+
+    /// Creates a new `BddManager` with the given variable order; the first label is the top-most variable.
+    pub fn with_variable_order<I, S>(labels: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut manager = Self::new();
+        for label in labels {
+            manager.level_of(label.as_ref());
+        }
+        manager
+    }
+
+    /// Creates a new `BddManager` whose variable order is derived from how often each label occurs
+    /// in the given expressions, most frequent first.
+    ///
+    /// # Example
+    /// ```
+    /// use accumulo_access::bdd::BddManager;
+    /// use accumulo_access::{Lexer, Parser};
+    /// let a = Parser::new(Lexer::new("A&(B|C)")).parse().unwrap();
+    /// let b = Parser::new(Lexer::new("(A&B)|(A&C)")).parse().unwrap();
+    ///
+    /// let mut manager = BddManager::for_expressions([&a, &b]);
+    /// assert_eq!(manager.variables()[0], "A");
+    /// assert_eq!(manager.compile(&a), manager.compile(&b));
+    /// ```
+    pub fn for_expressions<'a, I>(expressions: I) -> Self
+    where
+        I: IntoIterator<Item = &'a AuthorizationExpression>,
+    {
+        let mut frequencies: BTreeMap<&str, usize> = BTreeMap::new();
+        for expression in expressions {
+            count_labels(expression, &mut frequencies);
+        }
+        let mut labels: Vec<(&str, usize)> = frequencies.into_iter().collect();
+        labels.sort_by(|(a, x), (b, y)| y.cmp(x).then_with(|| a.cmp(b)));
+        Self::with_variable_order(labels.into_iter().map(|(label, _)| label))
+    }
+
+    /// The labels of all variables, in order.
+    pub fn variables(&self) -> &[String] {
+        &self.labels
+    }
+
+    /// The number of nodes (including the two terminals) owned by the manager.
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn level_of(&mut self, label: &str) -> u32 {
+        if let Some(level) = self.levels.get(label) {
+            return *level;
+        }
+        let level = u32::try_from(self.labels.len()).expect("too many BDD variables");
+        assert!(level < TERMINAL_LEVEL, "too many BDD variables");
+        self.labels.push(label.to_string());
+        self.levels.insert(label.to_string(), level);
+        level
+    }
+
+    fn node(&self, bdd: Bdd) -> Node {
+        self.nodes[bdd.0 as usize]
+    }
+
+    fn make(&mut self, level: u32, low: Bdd, high: Bdd) -> Bdd {
+        if low == high {
+            return low;
+        }
+        let node = Node { level, low, high };
+        if let Some(bdd) = self.unique.get(&node) {
+            return *bdd;
+        }
+        let bdd = Bdd(u32::try_from(self.nodes.len()).expect("too many BDD nodes"));
+        self.nodes.push(node);
+        self.unique.insert(node, bdd);
+        bdd
+    }
+
+    /// Returns the BDD of a single access token.
+    pub fn variable(&mut self, label: &str) -> Bdd {
+        let level = self.level_of(label);
+        self.make(level, Bdd::FALSE, Bdd::TRUE)
+    }
+
+    /// Combines two BDDs with the given operation.
+    pub fn apply(&mut self, op: BddOp, a: Bdd, b: Bdd) -> Bdd {
+        match (op, a, b) {
+            (_, a, b) if a == b => return a,
+            (BddOp::And, Bdd::FALSE, _) | (BddOp::And, _, Bdd::FALSE) => return Bdd::FALSE,
+            (BddOp::And, Bdd::TRUE, other) | (BddOp::And, other, Bdd::TRUE) => return other,
+            (BddOp::Or, Bdd::TRUE, _) | (BddOp::Or, _, Bdd::TRUE) => return Bdd::TRUE,
+            (BddOp::Or, Bdd::FALSE, other) | (BddOp::Or, other, Bdd::FALSE) => return other,
+            _ => {}
+        }
+        let key = (op, a.min(b), a.max(b));
+        if let Some(result) = self.computed.get(&key) {
+            return *result;
+        }
+        let (node_a, node_b) = (self.node(a), self.node(b));
+        let level = node_a.level.min(node_b.level);
+        let (a_low, a_high) = if node_a.level == level { (node_a.low, node_a.high) } else { (a, a) };
+        let (b_low, b_high) = if node_b.level == level { (node_b.low, node_b.high) } else { (b, b) };
+        let low = self.apply(op, a_low, b_low);
+        let high = self.apply(op, a_high, b_high);
+        let result = self.make(level, low, high);
+        self.computed.insert(key, result);
+        result
+    }
+
+    pub fn and(&mut self, a: Bdd, b: Bdd) -> Bdd {
+        self.apply(BddOp::And, a, b)
+    }
+
+    pub fn or(&mut self, a: Bdd, b: Bdd) -> Bdd {
+        self.apply(BddOp::Or, a, b)
+    }
+
+    /// Returns `true` if every authorization set satisfying `a` also satisfies `b`.
+    pub fn implies(&mut self, a: Bdd, b: Bdd) -> bool {
+        self.and(a, b) == a
+    }
+
+    /// Compiles an expression into a BDD.
+    pub fn compile(&mut self, expr: &AuthorizationExpression) -> Bdd {
+        let (op, nodes) = match expr {
+            AuthorizationExpression::Nil => return Bdd::TRUE,
+            AuthorizationExpression::AccessToken(token) => return self.variable(token),
+            AuthorizationExpression::ConjunctionOf(nodes) => (BddOp::And, nodes),
+            AuthorizationExpression::DisjunctionOf(nodes) => (BddOp::Or, nodes),
+        };
+        let mut operands: Vec<Bdd> = nodes.iter().map(|node| self.compile(node)).collect();
+        // Combining the bottom-most operands first keeps the intermediate results (and the recursion
+        // depth of `apply`) small for the wide, shallow expressions produced by automated labeling.
+        operands.sort_by_key(|bdd| std::cmp::Reverse(self.node(*bdd).level));
+        let identity = match op {
+            BddOp::And => Bdd::TRUE,
+            BddOp::Or => Bdd::FALSE,
+        };
+        operands.into_iter().fold(identity, |acc, operand| self.apply(op, operand, acc))
+    }
+
+    /// Evaluate the BDD with the given set of authorizations.
+    pub fn evaluate(&self, bdd: Bdd, authorizations: &Authorizations) -> bool {
+        let mut current = bdd;
+        while !current.is_terminal() {
+            let node = self.node(current);
+            current = if authorizations.contains(&self.labels[node.level as usize]) {
+                node.high
+            } else {
+                node.low
+            };
+        }
+        current == Bdd::TRUE
+    }
+
+    /// Returns the labels the BDD depends on, in variable order.
+    pub fn support(&self, bdd: Bdd) -> Vec<&str> {
+        self.support_levels(bdd).into_iter().map(|level| self.labels[level as usize].as_str()).collect()
+    }
+
+    fn support_levels(&self, bdd: Bdd) -> Vec<u32> {
+        let mut levels: Vec<u32> = self.reachable(bdd).into_iter()
+            .filter(|node| !node.is_terminal())
+            .map(|node| self.node(node).level)
+            .collect();
+        levels.sort_unstable();
+        levels.dedup();
+        levels
+    }
+
+    /// Counts the authorization sets over the labels in the BDD's [support](BddManager::support) that satisfy it.
+    ///
+    /// The count is exact up to 2^53 and saturates to infinity once it exceeds `f64::MAX`.
+    pub fn sat_count(&self, bdd: Bdd) -> f64 {
+        let support = self.support_levels(bdd);
+        // The position of a variable within the support; the terminals rank below all variables.
+        let rank = |level: u32| support.binary_search(&level).unwrap_or(support.len()) as i32;
+
+        let mut counts: HashMap<Bdd, f64> = HashMap::from([(Bdd::FALSE, 0.0), (Bdd::TRUE, 1.0)]);
+        for node in self.reachable(bdd) {
+            if node.is_terminal() {
+                continue;
+            }
+            let Node { level, low, high } = self.node(node);
+            // Every variable skipped between a node and its child may take either value.
+            let weight = |child: Bdd| match counts[&child] {
+                0.0 => 0.0,
+                count => count * 2f64.powi(rank(self.node(child).level) - rank(level) - 1),
+            };
+            let count = weight(low) + weight(high);
+            counts.insert(node, count);
+        }
+        counts[&bdd]
+    }
+
+    /// Finds a smallest authorization set that satisfies the BDD, or `None` if it is unsatisfiable.
+    pub fn minimum_authorizations(&self, bdd: Bdd) -> Option<Authorizations> {
+        let mut costs: HashMap<Bdd, Option<usize>> = HashMap::from([(Bdd::FALSE, None), (Bdd::TRUE, Some(0))]);
+        for node in self.reachable(bdd) {
+            if node.is_terminal() {
+                continue;
+            }
+            let Node { low, high, .. } = self.node(node);
+            let cost = match (costs[&low], costs[&high].map(|cost| cost + 1)) {
+                (Some(low), Some(high)) => Some(low.min(high)),
+                (low, high) => low.or(high),
+            };
+            costs.insert(node, cost);
+        }
+        costs[&bdd]?;
+
+        let mut labels = Vec::new();
+        let mut current = bdd;
+        while !current.is_terminal() {
+            let Node { level, low, high } = self.node(current);
+            let via_high = costs[&high].map(|cost| cost + 1);
+            if costs[&low].is_some() && costs[&low] <= via_high {
+                current = low;
+            } else {
+                labels.push(self.labels[level as usize].clone());
+                current = high;
+            }
+        }
+        Some(Authorizations::of(&labels))
+    }
+
+    /// Returns all nodes reachable from `bdd`, children before parents.
+    fn reachable(&self, bdd: Bdd) -> Vec<Bdd> {
+        // Nodes are only ever created after their children, so sorting by index is a topological order.
+        let mut seen = HashSet::from([bdd]);
+        let mut stack = vec![bdd];
+        while let Some(current) = stack.pop() {
+            if current.is_terminal() {
+                continue;
+            }
+            let node = self.node(current);
+            for child in [node.low, node.high] {
+                if seen.insert(child) {
+                    stack.push(child);
+                }
+            }
+        }
+        let mut nodes: Vec<Bdd> = seen.into_iter().collect();
+        nodes.sort_unstable();
+        nodes
+    }
+}
+
+fn count_labels<'a>(expr: &'a AuthorizationExpression, frequencies: &mut BTreeMap<&'a str, usize>) {
+    match expr {
+        AuthorizationExpression::AccessToken(token) => *frequencies.entry(token).or_default() += 1,
+        AuthorizationExpression::ConjunctionOf(nodes) | AuthorizationExpression::DisjunctionOf(nodes) => {
+            for node in nodes {
+                count_labels(node, frequencies);
+            }
+        }
+        AuthorizationExpression::Nil => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Lexer, Parser};
+    use rstest::rstest;
+
+    fn parse(expression: &str) -> AuthorizationExpression {
+        Parser::new(Lexer::new(expression)).parse().unwrap()
+    }
+
+    #[rstest]
+    #[case("A&(B|C)", "(A&B)|(A&C)", true)]
+    #[case("(A&B)|(A&C)|(A&B&D)", "A&(B|C)", true)]
+    #[case("A|(A&B)", "A", true)]
+    #[case("A&(B|C)", "A&B", false)]
+    #[case("A", "B", false)]
+    fn equivalent_expressions_share_a_node(#[case] a: &str, #[case] b: &str, #[case] expected: bool) {
+        let (a, b) = (parse(a), parse(b));
+        let mut manager = BddManager::for_expressions([&a, &b]);
+        let (bdd_a, bdd_b) = (manager.compile(&a), manager.compile(&b));
+        assert_eq!(bdd_a == bdd_b, expected);
+        assert_eq!(Ok(bdd_a == bdd_b), a.is_equivalent(&b));
+    }
+
+    #[rstest]
+    #[case("A&B", "A|C", true)]
+    #[case("A|C", "A", false)]
+    #[case("A", "", true)]
+    fn implication(#[case] a: &str, #[case] b: &str, #[case] expected: bool) {
+        let (a, b) = (parse(a), parse(b));
+        let mut manager = BddManager::new();
+        let (bdd_a, bdd_b) = (manager.compile(&a), manager.compile(&b));
+        assert_eq!(manager.implies(bdd_a, bdd_b), expected);
+        assert_eq!(Ok(manager.implies(bdd_a, bdd_b)), a.implies(&b));
+    }
+
+    #[rstest]
+    #[case("A", 1.0)]
+    #[case("A&B", 1.0)]
+    #[case("A|B", 3.0)]
+    #[case("A&(B|C)", 3.0)]
+    #[case("(A|B)&(C|D)", 9.0)]
+    #[case("(A&B)|C", 5.0)]
+    #[case("", 1.0)]
+    fn sat_count(#[case] expression: &str, #[case] expected: f64) {
+        let mut manager = BddManager::new();
+        let bdd = manager.compile(&parse(expression));
+        assert_eq!(manager.sat_count(bdd), expected);
+        assert_eq!(manager.sat_count(Bdd::FALSE), 0.0);
+    }
+
+    #[test]
+    fn evaluate_and_minimum_authorizations() {
+        let expr = parse("(A&B&C)|(D&(E|F))");
+        let mut manager = BddManager::for_expressions([&expr]);
+        let bdd = manager.compile(&expr);
+
+        let tokens = |tokens: &[&str]| Authorizations::of(&tokens.iter().map(|t| t.to_string()).collect::<Vec<_>>());
+        assert!(manager.evaluate(bdd, &tokens(&["D", "F"])));
+        assert!(!manager.evaluate(bdd, &tokens(&["A", "B", "F"])));
+
+        let minimum = manager.minimum_authorizations(bdd).unwrap();
+        assert_eq!(minimum.to_set().len(), 2);
+        assert!(expr.evaluate(&minimum.to_set()));
+        assert_eq!(manager.minimum_authorizations(Bdd::FALSE), None);
+        assert_eq!(manager.support(bdd), ["A", "B", "C", "D", "E", "F"]);
+    }
+
+    #[test]
+    fn scales_to_thousands_of_labels() {
+        // 1000 "any-of" groups of 5 labels each, as produced by automated labeling.
+        let expression = (0..1000)
+            .map(|group| format!("({})", (0..5).map(|i| format!("L{group}_{i}")).collect::<Vec<_>>().join("|")))
+            .collect::<Vec<_>>()
+            .join("&");
+        let expr = parse(&expression);
+        let mut manager = BddManager::for_expressions([&expr]);
+        let bdd = manager.compile(&expr);
+
+        assert_eq!(manager.support(bdd).len(), 5000);
+        let minimum = manager.minimum_authorizations(bdd).unwrap();
+        assert_eq!(minimum.to_set().len(), 1000);
+        assert!(manager.evaluate(bdd, &minimum));
+        assert!(expr.evaluate(&minimum.to_set()));
+        assert!(manager.sat_count(bdd).is_infinite());
+    }
+}
@@ -0,0 +1,502 @@
+// Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
+// Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE_MIT or LICENSE_APACHE files.
+
+//! A compact, versioned binary encoding of expressions.
+//!
+//! The layout is:
+//!
+//! ```text
+//! version: u8                      (BINARY_FORMAT_VERSION)
+//! label count: varint
+//! labels: (length: varint, UTF-8 bytes)*
+//! program: op*                     (to the end of the input)
+//! ```
+//!
+//! Varints are unsigned LEB128. The program is the expression in postfix order, each op being one
+//! varint whose two low bits are the opcode and whose remaining bits are the operand:
+//!
+//! | Opcode | Op | Operand |
+//! |---|---|---|
+//! | 0 | label | index into the label table |
+//! | 1 | and | number of operands taken from the stack, at least 2 |
+//! | 2 | or | number of operands taken from the stack, at least 2 |
+//! | 3 | nil | always 0 |
+//!
+//! Every label is stored once, so expressions repeating long labels encode smaller than their text,
+//! and always much smaller than the JSON tree. A valid program leaves exactly one node on the stack,
+//! uses `nil` only as the whole program (the empty expression), and only has labels that can be
+//! written in an expression (see [`is_valid_label`]). A scope with a single operand is encoded as the
+//! operand, which is what the `Parser` makes of it too.
+
+use std::collections::{HashMap, HashSet};
+
+use thiserror::Error;
+
+use crate::compiled::{CompiledExpression, LabelDictionary};
+use crate::{is_valid_label, AuthorizationExpression};
+
+/// The version written as the first byte of every encoded expression.
+pub const BINARY_FORMAT_VERSION: u8 = 1;
+
+/// The deepest nesting of operators accepted when decoding, which keeps the recursive operations on
+/// the decoded tree (evaluation, formatting, dropping) from overflowing the stack.
+pub const MAX_DECODE_DEPTH: usize = 512;
+
+const LABEL: u64 = 0;
+const AND: u64 = 1;
+const OR: u64 = 2;
+const NIL: u64 = 3;
+
+/// Errors returned when decoding an encoded expression. Offsets are byte positions in the input.
+#[derive(Error, Debug, PartialEq, Eq, Clone)]
+pub enum DecodeError {
+    /// The input ends in the middle of a value.
+    UnexpectedEnd,
+    /// The input was written by an unknown version of the format.
+    UnsupportedVersion(u8),
+    /// A varint does not fit in 64 bits.
+    VarintOverflow(usize),
+    /// A label is not valid UTF-8.
+    InvalidUtf8(usize),
+    /// A label is empty or contains characters that cannot be written in an expression.
+    InvalidLabel(usize),
+    /// An op refers to a label that is not in the label table.
+    LabelOutOfRange { offset: usize, index: u64 },
+    /// An op has an operand it doesn't allow, e.g. an operator with fewer than two operands.
+    InvalidOp(usize),
+    /// A `nil` op is used in a program that is more than the empty expression.
+    NestedNil(usize),
+    /// An operator takes more operands than there are on the stack.
+    StackUnderflow(usize),
+    /// Operators are nested deeper than [`MAX_DECODE_DEPTH`].
+    TooDeep(usize),
+    /// The program does not leave exactly one node (it left the given number).
+    Unbalanced(usize),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd => write!(f, "Unexpected end of encoded expression"),
+            DecodeError::UnsupportedVersion(version) => write!(f, "Unsupported encoding version {}", version),
+            DecodeError::VarintOverflow(offset) => write!(f, "Varint overflow at offset {}", offset),
+            DecodeError::InvalidUtf8(offset) => write!(f, "Label at offset {} is not valid UTF-8", offset),
+            DecodeError::InvalidLabel(offset) => write!(f, "Label at offset {} is empty or contains control characters", offset),
+            DecodeError::LabelOutOfRange { offset, index } => write!(f, "Label {} at offset {} is not in the label table", index, offset),
+            DecodeError::InvalidOp(offset) => write!(f, "Invalid op at offset {}", offset),
+            DecodeError::NestedNil(offset) => write!(f, "Nil at offset {} is only allowed as the whole expression", offset),
+            DecodeError::StackUnderflow(offset) => write!(f, "Operator at offset {} has too few operands", offset),
+            DecodeError::TooDeep(offset) => write!(f, "Operator at offset {} is nested deeper than {}", offset, MAX_DECODE_DEPTH),
+            DecodeError::Unbalanced(nodes) => write!(f, "Encoded expression leaves {} nodes instead of one", nodes),
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.position == self.bytes.len()
+    }
+
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.position
+    }
+
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        let byte = *self.bytes.get(self.position).ok_or(DecodeError::UnexpectedEnd)?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn varint(&mut self) -> Result<u64, DecodeError> {
+        let offset = self.position;
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            let bits = u64::from(byte & 0x7f);
+            if shift == 63 && bits > 1 {
+                return Err(DecodeError::VarintOverflow(offset));
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(DecodeError::VarintOverflow(offset))
+    }
+
+    fn slice(&mut self, len: u64) -> Result<&'a [u8], DecodeError> {
+        if len > self.remaining() as u64 {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let slice = &self.bytes[self.position..self.position + len as usize];
+        self.position += len as usize;
+        Ok(slice)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Instruction {
+    Label(usize),
+    And(usize),
+    Or(usize),
+    Nil,
+}
+
+/// Reads the next op of a program that has already been validated.
+fn next_instruction(reader: &mut Reader<'_>) -> Option<Instruction> {
+    if reader.is_empty() {
+        return None;
+    }
+    let op = reader.varint().expect("validated program");
+    let operand = (op >> 2) as usize;
+    Some(match op & 3 {
+        LABEL => Instruction::Label(operand),
+        AND => Instruction::And(operand),
+        OR => Instruction::Or(operand),
+        _ => Instruction::Nil,
+    })
+}
+
+/// A validated, encoded expression borrowing its labels and program from the input.
+///
+/// Evaluating it doesn't build the expression tree, so loading an expression costs one pass over
+/// the bytes and no string copies.
+///
+/// # Example
+/// ```
+/// use std::collections::HashSet;
+/// use accumulo_access::{AuthorizationExpression, EncodedExpression, Lexer, Parser};
+///
+/// let bytes = Parser::new(Lexer::new("A&(B|C)")).parse().unwrap().encode().unwrap();
+/// let encoded = EncodedExpression::new(&bytes).unwrap();
+/// assert!(encoded.evaluate(&HashSet::from(["A".to_string(), "C".to_string()])));
+/// assert_eq!(encoded.to_expression(), AuthorizationExpression::decode(&bytes).unwrap());
+/// ```
+#[derive(Debug, Clone)]
+pub struct EncodedExpression<'a> {
+    labels: Vec<&'a str>,
+    program: &'a [u8],
+}
+
+impl<'a> EncodedExpression<'a> {
+    /// Validates the encoded expression.
+    pub fn new(bytes: &'a [u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader { bytes, position: 0 };
+        let version = reader.byte()?;
+        if version != BINARY_FORMAT_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let count = reader.varint()?;
+        // Every label takes at least one byte, which bounds the allocation by the input size.
+        let mut labels = Vec::with_capacity(count.min(reader.remaining() as u64) as usize);
+        for _ in 0..count {
+            let len = reader.varint()?;
+            let offset = reader.position;
+            let label = std::str::from_utf8(reader.slice(len)?).map_err(|_| DecodeError::InvalidUtf8(offset))?;
+            if !is_valid_label(label) {
+                return Err(DecodeError::InvalidLabel(offset));
+            }
+            labels.push(label);
+        }
+
+        let program_start = reader.position;
+        let program = &bytes[program_start..];
+        // The nesting depth of every node on the stack.
+        let mut depths: Vec<usize> = Vec::new();
+        while !reader.is_empty() {
+            let offset = reader.position;
+            let op = reader.varint()?;
+            let operand = op >> 2;
+            match op & 3 {
+                LABEL if operand >= labels.len() as u64 => {
+                    return Err(DecodeError::LabelOutOfRange { offset, index: operand })
+                }
+                LABEL => depths.push(0),
+                NIL if operand != 0 => return Err(DecodeError::InvalidOp(offset)),
+                NIL if offset != program_start || !reader.is_empty() => return Err(DecodeError::NestedNil(offset)),
+                NIL => depths.push(0),
+                _ if operand < 2 => return Err(DecodeError::InvalidOp(offset)),
+                _ if operand > depths.len() as u64 => return Err(DecodeError::StackUnderflow(offset)),
+                _ => {
+                    let depth = depths.drain(depths.len() - operand as usize..).max().unwrap_or(0) + 1;
+                    if depth > MAX_DECODE_DEPTH {
+                        return Err(DecodeError::TooDeep(offset));
+                    }
+                    depths.push(depth);
+                }
+            }
+        }
+        match depths.len() {
+            1 => Ok(EncodedExpression { labels, program }),
+            nodes => Err(DecodeError::Unbalanced(nodes)),
+        }
+    }
+
+    /// The label table, in the order the labels first appear in the expression.
+    pub fn labels(&self) -> &[&'a str] {
+        &self.labels
+    }
+
+    fn instructions(&self) -> impl Iterator<Item = Instruction> + '_ {
+        let mut reader = Reader { bytes: self.program, position: 0 };
+        std::iter::from_fn(move || next_instruction(&mut reader))
+    }
+
+    /// Evaluate the expression with the given set of authorizations, without decoding it into a tree.
+    pub fn evaluate(&self, authorizations: &HashSet<String>) -> bool {
+        let mut stack: Vec<bool> = Vec::new();
+        for instruction in self.instructions() {
+            let value = match instruction {
+                Instruction::Label(index) => authorizations.contains(self.labels[index]),
+                Instruction::Nil => true,
+                Instruction::And(arity) => stack.drain(stack.len() - arity..).all(|value| value),
+                Instruction::Or(arity) => stack.drain(stack.len() - arity..).any(|value| value),
+            };
+            stack.push(value);
+        }
+        stack[0]
+    }
+
+    /// Decodes the expression tree.
+    pub fn to_expression(&self) -> AuthorizationExpression {
+        let mut stack: Vec<AuthorizationExpression> = Vec::new();
+        for instruction in self.instructions() {
+            let node = match instruction {
+                Instruction::Label(index) => AuthorizationExpression::AccessToken(self.labels[index].to_string()),
+                Instruction::Nil => AuthorizationExpression::Nil,
+                Instruction::And(arity) => AuthorizationExpression::ConjunctionOf(stack.split_off(stack.len() - arity)),
+                Instruction::Or(arity) => AuthorizationExpression::DisjunctionOf(stack.split_off(stack.len() - arity)),
+            };
+            stack.push(node);
+        }
+        stack.pop().expect("validated program")
+    }
+
+    /// Compiles the expression against a dictionary, interning its labels.
+    pub fn compile(&self, dictionary: &mut LabelDictionary) -> CompiledExpression {
+        dictionary.compile(&self.to_expression())
+    }
+}
+
+/// Writes the label table and program of one expression.
+#[derive(Default)]
+pub(crate) struct Encoder<'a> {
+    indices: HashMap<&'a str, u64>,
+    labels: Vec<&'a str>,
+    program: Vec<u8>,
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+impl<'a> Encoder<'a> {
+    pub(crate) fn label(&mut self, label: &'a str) {
+        let next = self.labels.len() as u64;
+        let index = *self.indices.entry(label).or_insert(next);
+        if index == next {
+            self.labels.push(label);
+        }
+        write_varint(&mut self.program, index << 2 | LABEL);
+    }
+
+    /// Writes an operator over the last `arity` nodes. A single operand stands for its scope, and an
+    /// empty conjunction (which is how compiled expressions hold the empty expression) is `nil`.
+    pub(crate) fn operator(&mut self, disjunction: bool, arity: usize) {
+        match (disjunction, arity) {
+            (_, 1) => {}
+            (false, 0) => self.nil(),
+            _ => write_varint(&mut self.program, (arity as u64) << 2 | if disjunction { OR } else { AND }),
+        }
+    }
+
+    pub(crate) fn nil(&mut self) {
+        write_varint(&mut self.program, NIL);
+    }
+
+    pub(crate) fn expression(&mut self, expr: &'a AuthorizationExpression) {
+        match expr {
+            AuthorizationExpression::AccessToken(token) => self.label(token),
+            AuthorizationExpression::Nil => self.nil(),
+            AuthorizationExpression::ConjunctionOf(nodes) | AuthorizationExpression::DisjunctionOf(nodes) => {
+                nodes.iter().for_each(|node| self.expression(node));
+                self.operator(matches!(expr, AuthorizationExpression::DisjunctionOf(_)), nodes.len());
+            }
+        }
+    }
+
+    pub(crate) fn finish(self) -> Vec<u8> {
+        let table_len: usize = self.labels.iter().map(|label| label.len() + 2).sum();
+        let mut out = Vec::with_capacity(2 + table_len + self.program.len());
+        out.push(BINARY_FORMAT_VERSION);
+        write_varint(&mut out, self.labels.len() as u64);
+        for label in self.labels {
+            write_varint(&mut out, label.len() as u64);
+            out.extend_from_slice(label.as_bytes());
+        }
+        out.extend_from_slice(&self.program);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InvalidTreeReason, JsonError, Lexer, Parser};
+    use rstest::rstest;
+
+    fn parse(expression: &str) -> AuthorizationExpression {
+        Parser::new(Lexer::new(expression)).parse().unwrap()
+    }
+
+    #[test]
+    fn layout_is_stable() {
+        let token = |label: &str| AuthorizationExpression::AccessToken(label.to_string());
+        let expr = AuthorizationExpression::ConjunctionOf(vec![
+            token("A"),
+            AuthorizationExpression::DisjunctionOf(vec![token("BC"), token("A")]),
+        ]);
+        // version, the labels "A" and "BC", then A, BC, A, or(2), and(2)
+        let expected = [1, 2, 1, b'A', 2, b'B', b'C', 0, 1 << 2, 0, 2 << 2 | 2, 2 << 2 | 1];
+        assert_eq!(expr.encode().unwrap(), expected);
+        assert_eq!(EncodedExpression::new(&expected).unwrap().labels(), ["A", "BC"]);
+    }
+
+    #[rstest]
+    #[case("")]
+    #[case("A")]
+    #[case("A&B")]
+    #[case("A|(B&\"label 🕺\")")]
+    #[case("(A&(B|C))|(A&D)")]
+    fn round_trips(#[case] expression: &str) {
+        let expr = parse(expression);
+        assert_eq!(AuthorizationExpression::decode(&expr.encode().unwrap()), Ok(expr));
+    }
+
+    // The format can't hold these trees, so encoding them fails instead of writing bytes that
+    // decoding rejects. Compiled expressions hold the empty expression as an empty conjunction, so
+    // they can't tell a nested empty conjunction from a nested empty expression.
+    #[rstest]
+    #[case(AuthorizationExpression::AccessToken(String::new()), "", InvalidTreeReason::EmptyLabel, InvalidTreeReason::EmptyLabel)]
+    #[case(AuthorizationExpression::ConjunctionOf(vec![parse("A"), AuthorizationExpression::AccessToken(String::new())]), "/and/1", InvalidTreeReason::EmptyLabel, InvalidTreeReason::EmptyLabel)]
+    #[case(AuthorizationExpression::DisjunctionOf(vec![]), "/or", InvalidTreeReason::NoOperands, InvalidTreeReason::NoOperands)]
+    #[case(AuthorizationExpression::ConjunctionOf(vec![parse("A"), AuthorizationExpression::ConjunctionOf(vec![])]), "/and/1/and", InvalidTreeReason::NoOperands, InvalidTreeReason::NestedNull)]
+    #[case(AuthorizationExpression::DisjunctionOf(vec![parse("A"), AuthorizationExpression::Nil]), "/or/1", InvalidTreeReason::NestedNull, InvalidTreeReason::NestedNull)]
+    fn trees_the_format_cant_hold_are_not_encoded(
+        #[case] expr: AuthorizationExpression,
+        #[case] pointer: &str,
+        #[case] reason: InvalidTreeReason,
+        #[case] compiled_reason: InvalidTreeReason,
+    ) {
+        assert_eq!(expr.encode(), Err(JsonError::InvalidTree { pointer: pointer.to_string(), reason }));
+
+        let mut dictionary = LabelDictionary::new();
+        assert_eq!(dictionary.compile(&expr).encode(&dictionary), Err(compiled_reason));
+    }
+
+    #[test]
+    fn single_operand_scopes_encode_as_the_operand() {
+        let token = AuthorizationExpression::AccessToken("A".to_string());
+        let expr = AuthorizationExpression::DisjunctionOf(vec![AuthorizationExpression::ConjunctionOf(vec![token.clone()])]);
+        assert_eq!(expr.encode(), token.encode());
+    }
+
+    #[rstest]
+    #[case("A&(B|C)", &["A"], false)]
+    #[case("A&(B|C)", &["A", "C"], true)]
+    #[case("A|B|C", &["C"], true)]
+    #[case("", &[], true)]
+    fn evaluates_without_decoding(#[case] expression: &str, #[case] tokens: &[&str], #[case] expected: bool) {
+        let bytes = parse(expression).encode().unwrap();
+        let authorizations = tokens.iter().map(|token| token.to_string()).collect();
+        assert_eq!(EncodedExpression::new(&bytes).unwrap().evaluate(&authorizations), expected);
+    }
+
+    #[test]
+    fn repeated_labels_encode_smaller_than_text_and_json() {
+        let expression = "(PROJECT_ALPHA&(SECRET|ADMIN))|(PROJECT_BETA&(SECRET|ADMIN))|(PROJECT_GAMMA&SECRET)";
+        let expr = parse(expression);
+        assert!(expr.encode().unwrap().len() < expression.len());
+        assert!(expr.encode().unwrap().len() * 2 < expr.to_json_str().len());
+    }
+
+    #[rstest]
+    #[case(&[], DecodeError::UnexpectedEnd)]
+    #[case(&[2, 0, 3], DecodeError::UnsupportedVersion(2))]
+    #[case(&[1, 1, 5, b'A'], DecodeError::UnexpectedEnd)]
+    #[case(&[1, 1, 1, 0xff], DecodeError::InvalidUtf8(3))]
+    #[case(&[1, 0, 4], DecodeError::LabelOutOfRange { offset: 2, index: 1 })]
+    #[case(&[1, 0, 7], DecodeError::InvalidOp(2))]
+    #[case(&[1, 1, 1, b'A', 0, 9], DecodeError::StackUnderflow(5))]
+    #[case(&[1, 1, 1, b'A', 0, 0], DecodeError::Unbalanced(2))]
+    #[case(&[1, 0, 2], DecodeError::InvalidOp(2))]
+    #[case(&[1, 1, 1, b'A', 0, 5], DecodeError::InvalidOp(5))]
+    #[case(&[1, 0, 3, 5], DecodeError::NestedNil(2))]
+    #[case(&[1, 1, 1, b'A', 0, 3, 9], DecodeError::NestedNil(5))]
+    #[case(&[1, 0, 3, 3], DecodeError::NestedNil(2))]
+    #[case(&[1, 1, 0, 3], DecodeError::InvalidLabel(3))]
+    #[case(&[1, 1, 3, b'a', b'\n', b'b', 0], DecodeError::InvalidLabel(3))]
+    #[case(&[1, 0], DecodeError::Unbalanced(0))]
+    #[case(&[1, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f], DecodeError::VarintOverflow(1))]
+    #[case(&[1, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01], DecodeError::UnexpectedEnd)]
+    #[case(&[1, 0, 0x80], DecodeError::UnexpectedEnd)]
+    fn corrupt_input_is_rejected(#[case] bytes: &[u8], #[case] error: DecodeError) {
+        assert_eq!(EncodedExpression::new(bytes).unwrap_err(), error);
+    }
+
+    // Every truncation and single-byte corruption of a valid encoding either fails or decodes to a
+    // tree that evaluates like the encoding itself.
+    #[test]
+    fn mutations_never_panic() {
+        let bytes = parse("(A&(B|\"c d\"))|(A&E)").encode().unwrap();
+        let authorizations = HashSet::from(["A".to_string(), "E".to_string()]);
+        let mut candidates: Vec<Vec<u8>> = (0..bytes.len()).map(|len| bytes[..len].to_vec()).collect();
+        for position in 0..bytes.len() {
+            for value in [0x00, 0x01, 0x03, 0x7f, 0x80, 0xff, bytes[position] ^ 0x04] {
+                let mut mutated = bytes.clone();
+                mutated[position] = value;
+                candidates.push(mutated);
+            }
+        }
+        for candidate in candidates {
+            if let Ok(encoded) = EncodedExpression::new(&candidate) {
+                assert_eq!(encoded.evaluate(&authorizations), encoded.to_expression().evaluate(&authorizations));
+            }
+        }
+    }
+
+    #[test]
+    fn deep_nesting_is_rejected() {
+        let and = (2 << 2 | AND) as u8;
+        let mut bytes = vec![BINARY_FORMAT_VERSION, 1, 1, b'A', LABEL as u8];
+        bytes.extend(std::iter::repeat_n([LABEL as u8, and], MAX_DECODE_DEPTH).flatten());
+        assert!(EncodedExpression::new(&bytes).is_ok());
+        bytes.extend([LABEL as u8, and]);
+        assert_eq!(EncodedExpression::new(&bytes).unwrap_err(), DecodeError::TooDeep(bytes.len() - 1));
+    }
+
+    #[test]
+    fn compiled_expressions_round_trip_through_another_dictionary() {
+        let expr = parse("A&(B|C)");
+        let mut dictionary = LabelDictionary::new();
+        dictionary.intern("unrelated");
+        let compiled = dictionary.compile(&expr);
+        let bytes = compiled.encode(&dictionary).unwrap();
+
+        let mut other = LabelDictionary::new();
+        let decoded = other.decode(&bytes).unwrap();
+        assert_eq!(other.len(), 3);
+        assert!(decoded.evaluate(&other.bitmap(["A", "C"])));
+        assert!(!decoded.evaluate(&other.bitmap(["B", "C"])));
+        assert_eq!(AuthorizationExpression::decode(&bytes), Ok(expr));
+    }
+}
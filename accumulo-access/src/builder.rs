@@ -0,0 +1,273 @@
+// Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
+// Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE_MIT or LICENSE_APACHE files.
+
+use crate::AuthorizationExpression;
+
+/// `Expr` builds expression trees fluently.
+///
+/// Chained operators extend the same scope (`a.and(b).and(c)` is `a&b&c`), while expressions passed
+/// as operands keep their own scope (`a.and(b.or(c))` is `a&(b|c)`). Single-operand scopes collapse
+/// to the operand, like parentheses around one label do in the `Parser`. The empty expression grants
+/// access to everyone, and an expression can't contain an empty scope, so as an operand it is
+/// dropped from conjunctions and turns disjunctions into the empty expression.
+///
+/// Labels are used as they are and quoted when the expression is written out; check labels from
+/// untrusted input with [`is_valid_label`](crate::is_valid_label).
+///
+/// # Example
+/// ```
+/// use accumulo_access::{AuthorizationExpression, Expr};
+///
+/// let expr: AuthorizationExpression = Expr::label("A").and(Expr::any_of(["B", "c d"])).into();
+/// assert_eq!(expr, "A&(B|\"c d\")".parse().unwrap());
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr(AuthorizationExpression);
+
+impl Expr {
+    /// A single label.
+    pub fn label(label: impl Into<String>) -> Self {
+        Expr(AuthorizationExpression::AccessToken(label.into()))
+    }
+
+    /// The empty expression, which grants access to everyone.
+    pub fn empty() -> Self {
+        Expr(AuthorizationExpression::Nil)
+    }
+
+    /// A scope satisfied when all operands are satisfied. Empty operands are dropped, as they
+    /// are always satisfied.
+    pub fn all_of<I>(operands: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<Expr>,
+    {
+        let nodes = operands
+            .into_iter()
+            .map(|operand| operand.into().0)
+            .filter(|node| *node != AuthorizationExpression::Nil)
+            .collect();
+        Self::scope(nodes, AuthorizationExpression::ConjunctionOf)
+    }
+
+    /// A scope satisfied when any operand is satisfied. With an empty operand, which grants access
+    /// to everyone, it is the empty expression.
+    pub fn any_of<I>(operands: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<Expr>,
+    {
+        let nodes: Vec<AuthorizationExpression> = operands.into_iter().map(|operand| operand.into().0).collect();
+        if nodes.contains(&AuthorizationExpression::Nil) {
+            return Expr::empty();
+        }
+        Self::scope(nodes, AuthorizationExpression::DisjunctionOf)
+    }
+
+    fn scope(mut nodes: Vec<AuthorizationExpression>, scope: fn(Vec<AuthorizationExpression>) -> AuthorizationExpression) -> Self {
+        match nodes.len() {
+            0 => Expr::empty(),
+            1 => Expr(nodes.pop().unwrap()),
+            _ => Expr(scope(nodes)),
+        }
+    }
+
+    /// Requires the other expression as well.
+    pub fn and(self, other: impl Into<Expr>) -> Self {
+        match (self.0, other.into().0) {
+            (node, AuthorizationExpression::Nil) | (AuthorizationExpression::Nil, node) => Expr(node),
+            (AuthorizationExpression::ConjunctionOf(mut nodes), other) => {
+                nodes.push(other);
+                Expr(AuthorizationExpression::ConjunctionOf(nodes))
+            }
+            (node, other) => Expr(AuthorizationExpression::ConjunctionOf(vec![node, other])),
+        }
+    }
+
+    /// Accepts the other expression instead. If either is empty, so is the result, as the empty
+    /// expression grants access to everyone.
+    pub fn or(self, other: impl Into<Expr>) -> Self {
+        match (self.0, other.into().0) {
+            (_, AuthorizationExpression::Nil) | (AuthorizationExpression::Nil, _) => Expr::empty(),
+            (AuthorizationExpression::DisjunctionOf(mut nodes), other) => {
+                nodes.push(other);
+                Expr(AuthorizationExpression::DisjunctionOf(nodes))
+            }
+            (node, other) => Expr(AuthorizationExpression::DisjunctionOf(vec![node, other])),
+        }
+    }
+
+    /// Returns the expression tree.
+    pub fn build(self) -> AuthorizationExpression {
+        self.0
+    }
+}
+
+impl From<&str> for Expr {
+    fn from(label: &str) -> Self {
+        Expr::label(label)
+    }
+}
+
+impl From<String> for Expr {
+    fn from(label: String) -> Self {
+        Expr::label(label)
+    }
+}
+
+impl From<AuthorizationExpression> for Expr {
+    fn from(expr: AuthorizationExpression) -> Self {
+        Expr(expr)
+    }
+}
+
+impl From<Expr> for AuthorizationExpression {
+    fn from(expr: Expr) -> Self {
+        expr.0
+    }
+}
+
+/// Builds an [`AuthorizationExpression`] from expression syntax, checked at compile time.
+///
+/// Labels are identifiers or string literals (for labels that need quoting, or contain `-`, `.`,
+/// `:` or `/`). Invalid string labels and mixed operators without parentheses are compile errors,
+/// and the tree is the same as the one the `Parser` builds from the same text.
+///
+/// # Example
+/// ```
+/// use accumulo_access::{access_expr, AuthorizationExpression};
+///
+/// let expr = access_expr!(A & (B | "c d") & "project-x");
+/// assert_eq!(expr, "A&(B|\"c d\")&project-x".parse::<AuthorizationExpression>().unwrap());
+/// assert_eq!(access_expr!(), AuthorizationExpression::Nil);
+/// ```
+///
+/// Mixing operators needs parentheses:
+/// ```compile_fail
+/// let expr = accumulo_access::access_expr!(A & B | C);
+/// ```
+///
+/// Parentheses must not be empty:
+/// ```compile_fail
+/// let expr = accumulo_access::access_expr!(A & ());
+/// ```
+///
+/// Labels must be valid:
+/// ```compile_fail
+/// let expr = accumulo_access::access_expr!(A & "tab\t");
+/// ```
+#[macro_export]
+macro_rules! access_expr {
+    () => {
+        $crate::AuthorizationExpression::Nil
+    };
+    ($($tokens:tt)+) => {
+        $crate::__access_expr!(@term [] [] $($tokens)+)
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __access_expr {
+    // Reads an operand: a parenthesized scope, an identifier or a string literal.
+    (@term [$($op:tt)?] [$($operands:expr,)*] () $($rest:tt)*) => {
+        ::core::compile_error!("expected a label or an expression inside the parentheses")
+    };
+    (@term [$($op:tt)?] [$($operands:expr,)*] ( $($inner:tt)* ) $($rest:tt)*) => {
+        $crate::__access_expr!(@operator [$($op)?] [$($operands,)* $crate::access_expr!($($inner)*),] $($rest)*)
+    };
+    (@term [$($op:tt)?] [$($operands:expr,)*] $label:ident $($rest:tt)*) => {
+        $crate::__access_expr!(@operator [$($op)?] [$($operands,)* $crate::__access_expr!(@label stringify!($label)),] $($rest)*)
+    };
+    (@term [$($op:tt)?] [$($operands:expr,)*] $label:literal $($rest:tt)*) => {
+        $crate::__access_expr!(@operator [$($op)?] [$($operands,)* $crate::__access_expr!(@label $label),] $($rest)*)
+    };
+    (@term [$($op:tt)?] [$($operands:expr,)*] $($rest:tt)*) => {
+        ::core::compile_error!("expected a label or a parenthesized expression")
+    };
+
+    // Reads the operator between two operands, which must be the same throughout the scope.
+    (@operator [$($op:tt)?] [$($operands:expr,)*]) => {
+        $crate::__access_expr!(@build [$($op)?] $($operands),*)
+    };
+    (@operator [$(&)?] [$($operands:expr,)*] & $($rest:tt)+) => {
+        $crate::__access_expr!(@term [&] [$($operands,)*] $($rest)+)
+    };
+    (@operator [$(|)?] [$($operands:expr,)*] | $($rest:tt)+) => {
+        $crate::__access_expr!(@term [|] [$($operands,)*] $($rest)+)
+    };
+    (@operator [&] [$($operands:expr,)*] | $($rest:tt)*) => {
+        ::core::compile_error!("Mixing operators ('&' and '|') requires parentheses")
+    };
+    (@operator [|] [$($operands:expr,)*] & $($rest:tt)*) => {
+        ::core::compile_error!("Mixing operators ('&' and '|') requires parentheses")
+    };
+    (@operator [$($op:tt)?] [$($operands:expr,)*] $($rest:tt)*) => {
+        ::core::compile_error!("expected '&' or '|' followed by a label or a parenthesized expression")
+    };
+
+    (@build [] $operand:expr) => {
+        $operand
+    };
+    (@build [&] $($operands:expr),+) => {
+        $crate::AuthorizationExpression::ConjunctionOf(::std::vec![$($operands),+])
+    };
+    (@build [|] $($operands:expr),+) => {
+        $crate::AuthorizationExpression::DisjunctionOf(::std::vec![$($operands),+])
+    };
+
+    (@label $label:expr) => {{
+        const LABEL: &str = $label;
+        const { ::core::assert!($crate::is_valid_label(LABEL), "invalid label in access_expr!") };
+        $crate::AuthorizationExpression::AccessToken(::std::string::String::from(LABEL))
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use crate::{Lexer, Parser};
+    use rstest::rstest;
+
+    fn parse(expression: &str) -> AuthorizationExpression {
+        Parser::new(Lexer::new(expression)).parse().unwrap()
+    }
+
+    #[rstest]
+    #[case(access_expr!(A), "A")]
+    #[case(access_expr!(A & B & C), "A&B&C")]
+    #[case(access_expr!(A | (B & "c d")), "A|(B&\"c d\")")]
+    #[case(access_expr!((A)), "(A)")]
+    #[case(access_expr!(((A | B)) & C), "((A|B))&C")]
+    #[case(access_expr!("a.b:c/d" | label_1), "a.b:c/d|label_1")]
+    #[case(access_expr!("say \"hi\"" & "🕺"), "\"say \\\"hi\\\"\"&\"🕺\"")]
+    fn macro_matches_parser(#[case] expr: AuthorizationExpression, #[case] expression: &str) {
+        assert_eq!(expr, parse(expression));
+    }
+
+    #[rstest]
+    #[case(Expr::label("A").and("B").and("C"), "A&B&C")]
+    #[case(Expr::label("A").and(Expr::any_of(["B", "C"])), "A&(B|C)")]
+    #[case(Expr::all_of(["A", "B"]).or("C"), "(A&B)|C")]
+    #[case(Expr::any_of(["only"]), "only")]
+    #[case(Expr::all_of(Vec::<Expr>::new()), "")]
+    #[case(Expr::empty().or("A"), "")]
+    #[case(Expr::label("c d").or(parse("E&F")), "\"c d\"|(E&F)")]
+    #[case(Expr::label("A").and(Expr::empty()), "A")]
+    #[case(Expr::label("A").or(Expr::empty()).or("B"), "")]
+    #[case(Expr::any_of([Expr::empty(), Expr::label("A"), Expr::empty()]), "")]
+    #[case(Expr::any_of([Expr::label("A"), Expr::all_of([Expr::empty()])]), "")]
+    #[case(Expr::all_of([Expr::label("A"), Expr::empty(), Expr::label("B")]), "A&B")]
+    fn builder_matches_parser(#[case] expr: Expr, #[case] expression: &str) {
+        assert_eq!(expr.build(), parse(expression));
+    }
+
+    #[test]
+    fn disjunctions_with_the_empty_expression_grant_everyone() {
+        let nobody = HashSet::new();
+        assert!(Expr::label("A").or(Expr::empty()).build().evaluate(&nobody));
+        assert!(Expr::any_of([Expr::label("A"), Expr::empty()]).build().evaluate(&nobody));
+        assert!(!Expr::label("A").and(Expr::empty()).build().evaluate(&nobody));
+    }
+}
@@ -1,7 +1,17 @@
 // Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
 // Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE_MIT or LICENSE_APACHE files.
 
-use cached::{proc_macro::cached, Cached, SizedCache};
+mod lru;
+mod sharded;
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::mem::size_of;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use crate::{AccessError, AuthorizationExpression, Lexer, Parser, ParserError};
+use self::sharded::{default_shard_count, ShardedStore};
 
 fn get_cache_size() -> usize {
     std::env::var("ACCUMULO_ACCESS_CACHE_SIZE")
@@ -10,29 +20,577 @@ fn get_cache_size() -> usize {
         .unwrap_or(20000)
 }
 
-#[cached(ty = "SizedCache<String, Result<bool, super::ParserError>>",
-create = "{ SizedCache::with_size(get_cache_size()) }",
-convert = r##"{ format!("{}{}", expression, tokens) }"##
-)]
+/// The capacity of the process-wide caches: a byte budget if `ACCUMULO_ACCESS_CACHE_BYTES` is set,
+/// otherwise the number of entries given by `ACCUMULO_ACCESS_CACHE_SIZE`.
+fn get_cache_capacity() -> CacheCapacity {
+    std::env::var("ACCUMULO_ACCESS_CACHE_BYTES")
+        .ok()
+        .and_then(|bytes| bytes.parse::<usize>().ok())
+        .map(CacheCapacity::Bytes)
+        .unwrap_or_else(|| CacheCapacity::Entries(get_cache_size()))
+}
+
+/// The bound of a cache: either a number of entries, or a budget of (estimated) bytes.
+///
+/// With a byte budget every entry is weighed by the size of its expression, its access tokens and
+/// the cached value, so a few huge machine-generated expressions can't blow up the memory usage.
+/// An integer converts into an entry count.
+///
+/// # Example
+/// ```
+/// use accumulo_access::caching::{AuthorizationCache, CacheCapacity};
+///
+/// let cache = AuthorizationCache::new(CacheCapacity::Bytes(64 * 1024 * 1024));
+/// cache.check_authorization("A&B", &["A".to_string()]).unwrap();
+/// assert!(cache.stats().bytes > 0);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheCapacity {
+    Entries(usize),
+    Bytes(usize),
+}
+
+impl From<usize> for CacheCapacity {
+    fn from(entries: usize) -> Self {
+        CacheCapacity::Entries(entries)
+    }
+}
+
+fn token_bytes(tokens: &[String]) -> usize {
+    tokens.iter().map(|token| size_of::<String>() + token.len()).sum()
+}
+
+// The stores keep every key twice, once more to find the least recently used entry, so the key is
+// counted twice; copies of a key share its authorizations.
+fn decision_weight(key: &DecisionKey, cached: &CachedDecision) -> usize {
+    let labels = cached.decisive_labels.as_deref().map_or(0, token_bytes);
+    let key_bytes = size_of::<DecisionKey>() + key.expression.len();
+    2 * key_bytes + size_of::<CachedDecision>() + token_bytes(key.authorizations.tokens()) + labels
+}
+
+fn tree_bytes(expr: &AuthorizationExpression) -> usize {
+    size_of::<AuthorizationExpression>() + match expr {
+        AuthorizationExpression::AccessToken(token) => token.len(),
+        AuthorizationExpression::ConjunctionOf(nodes) | AuthorizationExpression::DisjunctionOf(nodes) => nodes.iter().map(tree_bytes).sum(),
+        AuthorizationExpression::Nil => 0,
+    }
+}
+
+fn expression_weight(expression: &str, parsed: &Result<Arc<AuthorizationExpression>, ParserError>) -> usize {
+    let tree = parsed.as_ref().map_or(0, |expr| tree_bytes(expr));
+    2 * (size_of::<String>() + expression.len()) + size_of::<Result<Arc<AuthorizationExpression>, ParserError>>() + tree
+}
+
+/// A canonical, order-independent representation of a set of access tokens.
+///
+/// Two keys are equal iff they hold the same tokens; the fingerprint is only used for hashing.
+#[derive(Debug, Clone)]
+pub struct AuthorizationSetKey {
+    fingerprint: u64,
+    tokens: Arc<[String]>,
+}
+
+impl AuthorizationSetKey {
+    pub fn new(tokens: &[String]) -> Self {
+        let mut tokens = tokens.to_vec();
+        tokens.sort();
+        tokens.dedup();
+        let mut hasher = DefaultHasher::new();
+        tokens.hash(&mut hasher);
+        AuthorizationSetKey {
+            fingerprint: hasher.finish(),
+            tokens: tokens.into(),
+        }
+    }
+
+    /// A 64-bit hash of the (sorted and deduplicated) tokens.
+    pub fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+
+    pub fn tokens(&self) -> &[String] {
+        &self.tokens
+    }
+}
+
+impl PartialEq for AuthorizationSetKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.fingerprint == other.fingerprint && self.tokens == other.tokens
+    }
+}
+
+impl Eq for AuthorizationSetKey {}
+
+impl Hash for AuthorizationSetKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.fingerprint);
+    }
+}
+
+/// The key of a cached authorization decision: the expression and the set of access tokens it was evaluated with.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DecisionKey {
+    expression: String,
+    authorizations: AuthorizationSetKey,
+}
+
+impl DecisionKey {
+    pub fn new(expression: &str, authorizations: AuthorizationSetKey) -> Self {
+        DecisionKey {
+            expression: expression.to_string(),
+            authorizations,
+        }
+    }
+
+    pub fn expression(&self) -> &str {
+        &self.expression
+    }
+
+    pub fn authorizations(&self) -> &AuthorizationSetKey {
+        &self.authorizations
+    }
+}
+
+/// A cached authorization decision.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedDecision {
+    pub decision: Result<bool, ParserError>,
+    /// The labels the decision depended on, for the audit records of later hits (see
+    /// [`AuditRecord::decisive_labels`](crate::audit::AuditRecord::decisive_labels)). Only
+    /// collected while an audit sink is installed.
+    pub decisive_labels: Option<Arc<[String]>>,
+}
+
+/// A store of authorization decisions, used by [`AuthorizationCache`] and the process-wide
+/// [`check_authorization_csv`].
+///
+/// Implement this trait to back the decision cache with another cache library (e.g. to share its
+/// memory budget and eviction policy); [`AuthorizationCache`] is the in-crate implementation.
+/// Decisions are only ever reused for the same [`DecisionKey`], so implementations are free to drop
+/// entries at any time.
+///
+/// # Example
+/// ```
+/// use std::collections::HashMap;
+/// use std::sync::Mutex;
+/// use accumulo_access::caching::{AuthorizationSetKey, AuthzCacheStats, CachedDecision, DecisionCache, DecisionKey};
+///
+/// #[derive(Default)]
+/// struct UnboundedCache(Mutex<HashMap<DecisionKey, CachedDecision>>);
+///
+/// impl DecisionCache for UnboundedCache {
+///     fn get(&self, key: &DecisionKey) -> Option<CachedDecision> {
+///         self.0.lock().unwrap().get(key).cloned()
+///     }
+///     fn insert(&self, key: DecisionKey, decision: CachedDecision) {
+///         self.0.lock().unwrap().insert(key, decision);
+///     }
+///     fn invalidate(&self, key: &DecisionKey) {
+///         self.0.lock().unwrap().remove(key);
+///     }
+///     fn invalidate_authorizations(&self, authorizations: &AuthorizationSetKey) {
+///         self.0.lock().unwrap().retain(|key, _| key.authorizations() != authorizations);
+///     }
+///     fn invalidate_all(&self) {
+///         self.0.lock().unwrap().clear();
+///     }
+///     fn stats(&self) -> AuthzCacheStats {
+///         AuthzCacheStats::new(0, 0, self.0.lock().unwrap().len())
+///     }
+/// }
+///
+/// let cache = UnboundedCache::default();
+/// let tokens = vec!["A".to_string()];
+/// assert_eq!(cache.check_authorization("A|B", &tokens), Ok(true));
+/// assert_eq!(cache.stats().size, 1);
+///
+/// cache.invalidate_authorizations(&AuthorizationSetKey::new(&tokens));
+/// assert_eq!(cache.stats().size, 0);
+/// ```
+pub trait DecisionCache: Send + Sync {
+    /// Returns the cached decision for the key, if any.
+    fn get(&self, key: &DecisionKey) -> Option<CachedDecision>;
+
+    fn insert(&self, key: DecisionKey, decision: CachedDecision);
+
+    /// Removes the decision for the key, if it is cached.
+    fn invalidate(&self, key: &DecisionKey);
+
+    /// Removes every decision made for the given set of access tokens, e.g. when a user's
+    /// entitlements change.
+    fn invalidate_authorizations(&self, authorizations: &AuthorizationSetKey);
+
+    /// Removes all cached decisions.
+    fn invalidate_all(&self);
+
+    fn stats(&self) -> AuthzCacheStats;
+
+    /// The statistics of each independently locked part of the cache; a single entry unless the
+    /// implementation is sharded.
+    fn shard_stats(&self) -> Vec<AuthzCacheStats> {
+        vec![self.stats()]
+    }
+
+    /// Checks if the given set of access tokens authorizes access to the resource which protection is
+    /// described by the given expression, reusing a previous decision if one is cached.
+    fn check_authorization(&self, expression: &str, tokens: &[String]) -> Result<bool, AccessError> {
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!(
+            "decision_cache",
+            expression.fingerprint = %crate::trace::fingerprint(expression),
+            cache_hit = tracing::field::Empty,
+            decision = tracing::field::Empty,
+        );
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+
+        let key = DecisionKey::new(expression, AuthorizationSetKey::new(tokens));
+        let cached = self.get(&key);
+        #[cfg(feature = "metrics")]
+        crate::telemetry::record_cache_lookup(crate::telemetry::Cache::Decision, cached.is_some());
+        #[cfg(feature = "tracing")]
+        span.record("cache_hit", cached.is_some());
+        let decision = match cached {
+            Some(cached) => {
+                // Misses are audited by `crate::check_authorization`; hits reuse the decisive labels of the miss.
+                if let Some(sink) = crate::audit::audit_sink() {
+                    sink.record(&audit_cached_decision(expression, tokens, &cached));
+                }
+                cached.decision
+            }
+            None => {
+                let (decision, record) = crate::audited_check_authorization(expression, tokens);
+                let decisive_labels = record.map(|record| record.decisive_labels.into());
+                self.insert(key, CachedDecision { decision: decision.clone(), decisive_labels });
+                decision
+            }
+        };
+        #[cfg(feature = "tracing")]
+        if let Ok(granted) = decision {
+            span.record("decision", granted);
+        }
+        Ok(decision?)
+    }
+
+    /// Same as [`DecisionCache::check_authorization`], with the access tokens given as comma-separated values.
+    fn check_authorization_csv(&self, expression: &str, tokens: &str) -> Result<bool, AccessError> {
+        let tokens: Vec<String> = tokens.split(',').map(|s| s.to_string()).collect();
+        self.check_authorization(expression, &tokens)
+    }
+}
+
+/// The audit record of a decision reused from a decision cache. Decisions cached before the audit
+/// sink was installed have no decisive labels, so those are derived from the expression once more.
+fn audit_cached_decision(expression: &str, tokens: &[String], cached: &CachedDecision) -> crate::audit::AuditRecord {
+    let authorized_labels = tokens.iter().cloned().collect();
+    match (&cached.decisive_labels, &cached.decision) {
+        (Some(labels), decision) => crate::audit::AuditRecord::cached(expression, &authorized_labels, decision, labels),
+        (None, Err(error)) => crate::audit::AuditRecord::new(None, expression, &authorized_labels, Err(error)),
+        (None, Ok(granted)) => {
+            let auth_expr = Parser::new(Lexer::new(expression)).parse();
+            let outcome = auth_expr.as_ref().map(|auth_expr| (auth_expr, *granted));
+            crate::audit::AuditRecord::new(None, expression, &authorized_labels, outcome)
+        }
+    }
+}
+
+/// `AuthorizationCache` memoizes authorization decisions, keyed by expression and authorization set.
+/// It is the default [`DecisionCache`] implementation.
+///
+/// Entries are evicted in least-recently-used order once the capacity is reached, and optionally
+/// expire after a time-to-live. Each instance keeps its own statistics.
+///
+/// The cache is split into shards with separate locks, so concurrent lookups of different keys
+/// rarely block each other.
+///
+/// # Examples
+/// ```
+/// use std::time::Duration;
+/// use accumulo_access::caching::AuthorizationCache;
+///
+/// let cache = AuthorizationCache::with_ttl(1000, Duration::from_secs(60));
+/// let tokens = vec!["A".to_string(), "B".to_string()];
+/// assert_eq!(cache.check_authorization("A&B", &tokens), Ok(true));
+/// assert_eq!(cache.check_authorization("A&B", &tokens), Ok(true));
+///
+/// let stats = cache.stats();
+/// assert_eq!((stats.hits, stats.misses, stats.size), (1, 1, 1));
+/// ```
+pub struct AuthorizationCache {
+    store: ShardedStore<DecisionKey, CachedDecision>,
+}
+
+impl AuthorizationCache {
+    /// Creates a new `AuthorizationCache` holding at most `capacity` decisions (or bytes, see [`CacheCapacity`]).
+    pub fn new(capacity: impl Into<CacheCapacity>) -> Self {
+        Self::with_store(ShardedStore::new(capacity.into(), None, default_shard_count(), decision_weight))
+    }
+
+    /// Creates a new `AuthorizationCache` holding at most `capacity` decisions, each for at most `ttl`.
+    pub fn with_ttl(capacity: impl Into<CacheCapacity>, ttl: Duration) -> Self {
+        Self::with_store(ShardedStore::new(capacity.into(), Some(ttl), default_shard_count(), decision_weight))
+    }
+
+    fn with_store(store: ShardedStore<DecisionKey, CachedDecision>) -> Self {
+        AuthorizationCache { store }
+    }
+
+    /// Splits the cache into `shards` independently locked shards (by default a few per core).
+    /// The capacity is divided evenly between the shards; with a byte budget, the number of shards
+    /// is limited so that each gets at least 64 KiB.
+    pub fn with_shards(self, shards: usize) -> Self {
+        Self::with_store(self.store.with_shards(shards))
+    }
+
+    pub fn capacity(&self) -> CacheCapacity {
+        self.store.capacity()
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.store.shard_count()
+    }
+
+    /// Checks if the given set of access tokens authorizes access to the resource which protection is
+    /// described by the given expression, reusing a previous decision if one is cached.
+    pub fn check_authorization(&self, expression: &str, tokens: &[String]) -> Result<bool, AccessError> {
+        DecisionCache::check_authorization(self, expression, tokens)
+    }
+
+    /// Same as [`AuthorizationCache::check_authorization`], with the access tokens given as comma-separated values.
+    pub fn check_authorization_csv(&self, expression: &str, tokens: &str) -> Result<bool, AccessError> {
+        DecisionCache::check_authorization_csv(self, expression, tokens)
+    }
+
+    /// Removes all cached decisions. The hit and miss counters are kept.
+    pub fn clear(&self) {
+        self.store.clear();
+    }
+
+    /// The statistics of all shards combined.
+    pub fn stats(&self) -> AuthzCacheStats {
+        self.store.stats()
+    }
+
+    /// The statistics of each shard.
+    pub fn shard_stats(&self) -> Vec<AuthzCacheStats> {
+        self.store.shard_stats()
+    }
+}
+
+impl DecisionCache for AuthorizationCache {
+    fn get(&self, key: &DecisionKey) -> Option<CachedDecision> {
+        self.store.get(key)
+    }
+
+    fn insert(&self, key: DecisionKey, decision: CachedDecision) {
+        let _evicted = self.store.insert(key, decision);
+        #[cfg(feature = "metrics")]
+        crate::telemetry::record_evictions(crate::telemetry::Cache::Decision, _evicted);
+    }
+
+    fn invalidate(&self, key: &DecisionKey) {
+        self.store.remove(key);
+    }
+
+    /// Scans every shard, locking one at a time, so lookups of other shards proceed meanwhile.
+    fn invalidate_authorizations(&self, authorizations: &AuthorizationSetKey) {
+        self.store.retain(|key| key.authorizations() != authorizations);
+    }
+
+    fn invalidate_all(&self) {
+        self.clear();
+    }
+
+    fn stats(&self) -> AuthzCacheStats {
+        AuthorizationCache::stats(self)
+    }
+
+    fn shard_stats(&self) -> Vec<AuthzCacheStats> {
+        AuthorizationCache::shard_stats(self)
+    }
+}
+
+/// `ExpressionCache` memoizes parsed expressions, independently of the authorizations they are evaluated with.
+///
+/// Invalid expressions are cached as well, so repeatedly checking them fails fast with the same `ParserError`.
+///
+/// # Examples
+/// ```
+/// use std::sync::Arc;
+/// use accumulo_access::caching::ExpressionCache;
+///
+/// let cache = ExpressionCache::new(1000);
+/// let first = cache.parse("A&(B|C)").unwrap();
+/// let second = cache.parse("A&(B|C)").unwrap();
+/// assert!(Arc::ptr_eq(&first, &second));
+///
+/// assert_eq!(cache.check_authorization("A&(B|C)", &["A".to_string(), "C".to_string()]), Ok(true));
+/// assert!(cache.parse("A&B|C").is_err());
+/// ```
+pub struct ExpressionCache {
+    store: ShardedStore<String, Result<Arc<AuthorizationExpression>, ParserError>>,
+}
+
+impl ExpressionCache {
+    /// Creates a new `ExpressionCache` holding at most `capacity` expressions (or bytes, see [`CacheCapacity`]).
+    pub fn new(capacity: impl Into<CacheCapacity>) -> Self {
+        ExpressionCache {
+            store: ShardedStore::new(capacity.into(), None, default_shard_count(), |expression, parsed| expression_weight(expression, parsed)),
+        }
+    }
+
+    /// Splits the cache into `shards` independently locked shards (by default a few per core).
+    /// The capacity is divided evenly between the shards; with a byte budget, the number of shards
+    /// is limited so that each gets at least 64 KiB.
+    pub fn with_shards(self, shards: usize) -> Self {
+        ExpressionCache {
+            store: self.store.with_shards(shards),
+        }
+    }
+
+    pub fn capacity(&self) -> CacheCapacity {
+        self.store.capacity()
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.store.shard_count()
+    }
+
+    /// Parses the expression, or returns the result of parsing it before.
+    pub fn parse(&self, expression: &str) -> Result<Arc<AuthorizationExpression>, ParserError> {
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!(
+            "expression_cache",
+            expression.fingerprint = %crate::trace::fingerprint(expression),
+            cache_hit = tracing::field::Empty,
+        );
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+
+        let cached = self.store.get(expression);
+        #[cfg(feature = "metrics")]
+        crate::telemetry::record_cache_lookup(crate::telemetry::Cache::Expression, cached.is_some());
+        #[cfg(feature = "tracing")]
+        span.record("cache_hit", cached.is_some());
+        if let Some(parsed) = cached {
+            return parsed;
+        }
+        let parsed = Parser::new(Lexer::new(expression)).parse().map(Arc::new);
+        let _evicted = self.store.insert(expression.to_string(), parsed.clone());
+        #[cfg(feature = "metrics")]
+        crate::telemetry::record_evictions(crate::telemetry::Cache::Expression, _evicted);
+        parsed
+    }
+
+    /// Checks if the given set of access tokens authorizes access to the resource which protection is
+    /// described by the given expression, parsing the expression only if it isn't cached.
+    pub fn check_authorization(&self, expression: &str, tokens: &[String]) -> Result<bool, AccessError> {
+        let auth_expr = self.parse(expression);
+        let authorized_labels = tokens.iter().cloned().collect();
+        let result = auth_expr.as_ref().map(|auth_expr| auth_expr.evaluate_parsed_from(Some(expression), &authorized_labels));
+        if let Some(sink) = crate::audit::audit_sink() {
+            let outcome = auth_expr.as_ref().map(|auth_expr| (auth_expr.as_ref(), result == Ok(true)));
+            sink.record(&crate::audit::AuditRecord::new(None, expression, &authorized_labels, outcome));
+        }
+        result.map_err(|error| AccessError::Parse(error.clone()))
+    }
+
+    /// Removes all cached expressions. The hit and miss counters are kept.
+    pub fn clear(&self) {
+        self.store.clear();
+    }
+
+    /// The statistics of all shards combined.
+    pub fn stats(&self) -> AuthzCacheStats {
+        self.store.stats()
+    }
+
+    /// The statistics of each shard.
+    pub fn shard_stats(&self) -> Vec<AuthzCacheStats> {
+        self.store.shard_stats()
+    }
+}
+
+static DECISION_CACHE: OnceLock<Arc<dyn DecisionCache>> = OnceLock::new();
+
+fn default_cache() -> &'static dyn DecisionCache {
+    DECISION_CACHE
+        .get_or_init(|| Arc::new(AuthorizationCache::new(get_cache_capacity())))
+        .as_ref()
+}
+
+/// Installs the backend of the process-wide decision cache used by [`check_authorization_csv`].
+///
+/// This must happen before the first decision is cached; once the default [`AuthorizationCache`]
+/// (sized by the `ACCUMULO_ACCESS_CACHE_SIZE` environment variable) or another backend is in use,
+/// the given cache is handed back as an error.
+///
+/// # Example
+/// ```
+/// use std::sync::Arc;
+/// use accumulo_access::caching::{self, AuthorizationCache};
+///
+/// let backend = Arc::new(AuthorizationCache::new(100_000).with_shards(64));
+/// assert!(caching::set_decision_cache(backend).is_ok());
+/// assert_eq!(caching::check_authorization_csv("A&B".to_string(), "A,B".to_string()), Ok(true));
+/// ```
+pub fn set_decision_cache(cache: Arc<dyn DecisionCache>) -> Result<(), Arc<dyn DecisionCache>> {
+    DECISION_CACHE.set(cache)
+}
+
+fn default_expression_cache() -> &'static ExpressionCache {
+    static CACHE: OnceLock<ExpressionCache> = OnceLock::new();
+    CACHE.get_or_init(|| ExpressionCache::new(get_cache_capacity()))
+}
+
+/// Parses the expression using a process-wide parse cache sized by the `ACCUMULO_ACCESS_CACHE_SIZE` environment variable.
+pub fn parse_expression(expression: &str) -> Result<Arc<AuthorizationExpression>, ParserError> {
+    default_expression_cache().parse(expression)
+}
+
+/// Checks if the given set of access tokens authorizes access to the resource which protection is described by the given expression,
+/// using a process-wide parse cache sized by the `ACCUMULO_ACCESS_CACHE_SIZE` environment variable (default: 20000 entries).
+///
+/// Unlike [`check_authorization_csv`], the decision itself is not cached, so this also hits the cache
+/// when the same expression is evaluated for many different authorization sets.
+pub fn check_authorization(expression: &str, tokens: &[String]) -> Result<bool, AccessError> {
+    default_expression_cache().check_authorization(expression, tokens)
+}
+
+pub fn clear_expression_cache() {
+    default_expression_cache().clear();
+}
+
+pub fn expression_cache_stats() -> AuthzCacheStats {
+    default_expression_cache().stats()
+}
+
+/// Checks if the given set of access tokens authorizes access to the resource which protection is described by the given expression,
+/// using a process-wide cache sized by the `ACCUMULO_ACCESS_CACHE_SIZE` environment variable (default: 20000 entries).
 pub fn check_authorization_csv(
     expression: String,
     tokens: String,
-) -> Result<bool, super::ParserError> {
-    super::check_authorization_csv(expression, tokens)
+) -> Result<bool, AccessError> {
+    default_cache().check_authorization_csv(&expression, &tokens)
 }
 
-pub fn clear_authz_cache() -> Result<(), String> {
-    let mut cache = crate::caching::CHECK_AUTHORIZATION_CSV
-        .lock()
-        .map_err(|e| format!("Failed to lock cache: {}", e))?;
-    cache.cache_clear();
-    Ok(())
+pub fn clear_authz_cache() {
+    default_cache().invalidate_all();
 }
 
+/// Removes every decision the process-wide cache holds for the given set of access tokens.
+pub fn invalidate_authorizations(tokens: &[String]) {
+    default_cache().invalidate_authorizations(&AuthorizationSetKey::new(tokens));
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct AuthzCacheStats {
     pub hits: u64,
     pub misses: u64,
+    /// The number of cached entries.
     pub size: usize,
+    /// The estimated memory used by the cached entries, in bytes.
+    pub bytes: usize,
 }
 
 impl AuthzCacheStats {
@@ -41,14 +599,266 @@ impl AuthzCacheStats {
             hits,
             misses,
             size,
+            bytes: 0,
         }
     }
+
+    pub fn with_bytes(mut self, bytes: usize) -> Self {
+        self.bytes = bytes;
+        self
+    }
+}
+
+/// The statistics of the process-wide decision cache, aggregated over all shards.
+pub fn authz_cache_stats() -> AuthzCacheStats {
+    default_cache().stats()
+}
+
+/// The statistics of the process-wide decision cache, without creating it if no decision has been
+/// cached yet (so a backend can still be installed with [`set_decision_cache`]).
+#[cfg(feature = "metrics")]
+pub(crate) fn installed_authz_cache_stats() -> Option<AuthzCacheStats> {
+    DECISION_CACHE.get().map(|cache| cache.stats())
+}
+
+/// The statistics of each shard of the process-wide decision cache.
+pub fn authz_cache_shard_stats() -> Vec<AuthzCacheStats> {
+    default_cache().shard_stats()
 }
 
-pub fn authz_cache_stats() -> Result<AuthzCacheStats, String> {
-    let cache = crate::caching::CHECK_AUTHORIZATION_CSV
-        .lock()
-        .map_err(|e| format!("Failed to lock cache: {}", e))?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn tokens(csv: &str) -> Vec<String> {
+        csv.split(',').map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn keys_do_not_collide_on_concatenation() {
+        let cache = AuthorizationCache::new(10);
+        // Both used to share the key "AB,A".
+        assert_eq!(cache.check_authorization_csv("A", "B,A"), Ok(true));
+        assert_eq!(cache.check_authorization_csv("AB", ",A"), Ok(false));
+        assert_eq!(cache.stats().misses, 2);
+    }
+
+    #[test]
+    fn authorization_set_key_is_order_independent() {
+        assert_eq!(AuthorizationSetKey::new(&tokens("A,B,A")), AuthorizationSetKey::new(&tokens("B,A")));
+        assert_ne!(AuthorizationSetKey::new(&tokens("A,B")), AuthorizationSetKey::new(&tokens("A,BC")));
+
+        let cache = AuthorizationCache::new(10);
+        assert_eq!(cache.check_authorization("A&B", &tokens("A,B")), Ok(true));
+        assert_eq!(cache.check_authorization("A&B", &tokens("B,A")), Ok(true));
+        assert_eq!(cache.stats().hits, 1);
+    }
+
+    #[test]
+    fn instances_have_separate_capacity_and_stats() {
+        let small = AuthorizationCache::new(1);
+        let large = AuthorizationCache::new(10).with_shards(1);
+        for expression in ["A", "B", "A|B"] {
+            small.check_authorization(expression, &tokens("A")).unwrap();
+            large.check_authorization(expression, &tokens("A")).unwrap();
+        }
+        large.check_authorization("A", &tokens("A")).unwrap();
+
+        assert_eq!(small.stats().size, 1);
+        assert_eq!(large.stats().size, 3);
+        assert_eq!(large.stats().hits, 1);
+        assert_eq!(small.stats().hits, 0);
+    }
+
+    #[test]
+    fn parse_errors_are_cached() {
+        let cache = AuthorizationCache::new(10);
+        assert_eq!(cache.check_authorization("A&B|C", &tokens("A")), Err(AccessError::Parse(ParserError::MixingOperators)));
+        assert_eq!(cache.check_authorization("A&B|C", &tokens("A")), Err(AccessError::Parse(ParserError::MixingOperators)));
+        assert_eq!(cache.stats().hits, 1);
+    }
+
+    #[test]
+    fn expression_cache_hits_across_authorization_sets() {
+        let cache = ExpressionCache::new(10);
+        for user in ["A", "B", "C", "A,B"] {
+            cache.check_authorization("A|B", &tokens(user)).unwrap();
+        }
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.size), (3, 1, 1));
+    }
+
+    #[test]
+    fn expression_cache_caches_parser_errors() {
+        let cache = ExpressionCache::new(10);
+        assert_eq!(cache.parse("A&B|C"), Err(ParserError::MixingOperators));
+        assert_eq!(cache.check_authorization("A&B|C", &tokens("A")), Err(AccessError::Parse(ParserError::MixingOperators)));
+        assert_eq!(cache.stats().hits, 1);
+    }
+
+    #[test]
+    fn expression_cache_is_bounded() {
+        let cache = ExpressionCache::new(2).with_shards(1);
+        for expression in ["A", "B", "C", "D"] {
+            cache.parse(expression).unwrap();
+        }
+        assert_eq!(cache.stats().size, 2);
+    }
+
+    #[test]
+    fn invalidate_authorizations_only_drops_that_set() {
+        let cache = AuthorizationCache::new(100);
+        for expression in ["A", "B", "A|B"] {
+            cache.check_authorization(expression, &tokens("A,B")).unwrap();
+            cache.check_authorization(expression, &tokens("A")).unwrap();
+        }
+        cache.invalidate_authorizations(&AuthorizationSetKey::new(&tokens("B,A")));
+        assert_eq!(cache.stats().size, 3);
+
+        cache.check_authorization("A", &tokens("A")).unwrap();
+        cache.check_authorization("A", &tokens("A,B")).unwrap();
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 7));
+    }
+
+    #[test]
+    fn invalidate_drops_one_decision() {
+        let cache = AuthorizationCache::new(10);
+        cache.check_authorization("A", &tokens("A")).unwrap();
+        cache.check_authorization("B", &tokens("A")).unwrap();
+        cache.invalidate(&DecisionKey::new("A", AuthorizationSetKey::new(&tokens("A"))));
+        assert_eq!(cache.stats().size, 1);
+    }
+
+    #[test]
+    fn byte_budget_bounds_memory_usage() {
+        let cache = AuthorizationCache::new(CacheCapacity::Bytes(100 * 1024)).with_shards(1);
+        let large = (0..2000).map(|i| format!("L{}", i)).collect::<Vec<_>>().join("|");
+        cache.check_authorization(&large, &tokens("L1")).unwrap();
+        let stats = cache.stats();
+        assert_eq!(stats.size, 1);
+        assert!(stats.bytes > large.len(), "{} bytes", stats.bytes);
+
+        // Small decisions are weighed by their size, so many more of them fit.
+        for i in 0..1000 {
+            cache.check_authorization(&format!("L{}", i), &tokens("L1")).unwrap();
+        }
+        let stats = cache.stats();
+        assert!(stats.size > 500, "{} entries", stats.size);
+        assert!(stats.bytes <= 100 * 1024);
+
+        cache.clear();
+        assert_eq!(cache.stats().bytes, 0);
+    }
+
+    // The key is stored twice, so a long expression weighs at least twice its length.
+    #[test]
+    fn keys_are_counted_as_often_as_they_are_stored() {
+        let long = (0..2000).map(|i| format!("L{}", i)).collect::<Vec<_>>().join("|");
+
+        let decisions = AuthorizationCache::new(CacheCapacity::Bytes(1024 * 1024)).with_shards(1);
+        decisions.check_authorization(&long, &tokens("L1")).unwrap();
+        assert!(decisions.stats().bytes > 2 * long.len(), "{} bytes", decisions.stats().bytes);
+
+        let expressions = ExpressionCache::new(CacheCapacity::Bytes(1024 * 1024));
+        expressions.parse("A").unwrap();
+        let small = expressions.stats().bytes;
+        let label = "L".repeat(10_000);
+        expressions.parse(&label).unwrap();
+        assert!(expressions.stats().bytes - small >= 3 * label.len(), "{} bytes", expressions.stats().bytes - small);
+    }
+
+    #[test]
+    fn expression_cache_weighs_parsed_trees() {
+        let cache = ExpressionCache::new(CacheCapacity::Bytes(1024 * 1024));
+        cache.parse("A").unwrap();
+        let small = cache.stats().bytes;
+        cache.parse("A&B&C&D&E&F&G&H").unwrap();
+        // Eight labels under a conjunction are eight more nodes than the single label.
+        assert!(cache.stats().bytes - small > 8 * size_of::<AuthorizationExpression>());
+    }
+
+    #[test]
+    fn expired_decisions_are_recomputed() {
+        let cache = AuthorizationCache::with_ttl(10, Duration::ZERO);
+        cache.check_authorization("A", &tokens("A")).unwrap();
+        cache.check_authorization("A", &tokens("A")).unwrap();
+        assert_eq!(cache.stats().misses, 2);
+    }
+
+    // Each thread has its own authorization set, so no key is shared and the counts are exact.
+    #[test]
+    fn concurrent_lookups_are_counted_once_over_shards() {
+        const THREADS: usize = 8;
+        const LOOKUPS_PER_THREAD: usize = 5_000;
+        let expressions: Vec<String> = (0..THREADS).map(|i| format!("label{}|(A&B)", i)).collect();
+
+        for shards in [1, 16] {
+            let cache = AuthorizationCache::new(10_000).with_shards(shards);
+            std::thread::scope(|scope| {
+                for thread in 0..THREADS {
+                    let (cache, expressions) = (&cache, &expressions);
+                    scope.spawn(move || {
+                        let tokens = vec![format!("label{}", thread)];
+                        for i in 0..LOOKUPS_PER_THREAD {
+                            let expression = i % expressions.len();
+                            let granted = cache.check_authorization(&expressions[expression], &tokens).unwrap();
+                            assert_eq!(granted, expression == thread);
+                        }
+                    });
+                }
+            });
 
-    Ok(AuthzCacheStats::new(cache.cache_hits().unwrap(), cache.cache_misses().unwrap(), cache.cache_size()))
+            let keys = THREADS * expressions.len();
+            let stats = cache.stats();
+            assert_eq!(stats.misses, keys as u64);
+            assert_eq!(stats.hits, (THREADS * LOOKUPS_PER_THREAD - keys) as u64);
+            assert_eq!(stats.size, keys);
+
+            let shard_stats = cache.shard_stats();
+            assert_eq!(shard_stats.len(), shards);
+            assert_eq!(shard_stats.iter().map(|shard| shard.hits + shard.misses).sum::<u64>(), (THREADS * LOOKUPS_PER_THREAD) as u64);
+            assert_eq!(shard_stats.iter().map(|shard| shard.size).sum::<usize>(), keys);
+            if shards > 1 {
+                assert!(shard_stats.iter().filter(|shard| shard.size > 0).count() > 1, "the keys are spread over the shards");
+            }
+        }
+    }
+
+    fn lookups_per_second(cache: &AuthorizationCache, threads: usize, expressions: &[String]) -> f64 {
+        const LOOKUPS_PER_THREAD: usize = 200_000;
+        let start = Instant::now();
+        std::thread::scope(|scope| {
+            for thread in 0..threads {
+                scope.spawn(move || {
+                    let tokens = vec![format!("label{}", thread)];
+                    for i in 0..LOOKUPS_PER_THREAD {
+                        let expression = i % expressions.len();
+                        let granted = cache.check_authorization(&expressions[expression], &tokens).unwrap();
+                        assert_eq!(granted, expression == thread);
+                    }
+                });
+            }
+        });
+        (threads * LOOKUPS_PER_THREAD) as f64 / start.elapsed().as_secs_f64()
+    }
+
+    // Timing dependent, so run it explicitly in a release build:
+    // `cargo test --release -p accumulo-access -- --ignored --nocapture lookups_scale`.
+    #[test]
+    #[ignore]
+    fn concurrent_lookups_scale_over_shards() {
+        let cpus = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let threads = cpus.clamp(4, 64);
+        let expressions: Vec<String> = (0..threads).map(|i| format!("label{}|(A&B)", i)).collect();
+
+        let single = lookups_per_second(&AuthorizationCache::new(10_000).with_shards(1), threads, &expressions);
+        let sharded = lookups_per_second(&AuthorizationCache::new(10_000).with_shards(default_shard_count()), threads, &expressions);
+        println!("{} thread(s): {:.0} lookups/s with 1 shard, {:.0} lookups/s with {} shards", threads, single, sharded, default_shard_count());
+        // Threads only contend for a lock when they run in parallel.
+        if cpus > 1 {
+            assert!(sharded > single, "sharding the cache should reduce lock contention");
+        }
+    }
 }
@@ -0,0 +1,236 @@
+// Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
+// Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE_MIT or LICENSE_APACHE files.
+
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use super::CacheCapacity;
+
+struct Entry<V> {
+    value: V,
+    tick: u64,
+    inserted: Instant,
+    bytes: usize,
+}
+
+/// A bounded map evicting the least recently used entry, with an optional time-to-live.
+///
+/// Every entry is weighed in bytes by the `weigher`; the store is bounded either by the number of
+/// entries or by the total weight. The key is stored twice, in the map and in the recency order, so
+/// the weigher must count it twice.
+pub(crate) struct LruStore<K, V> {
+    capacity: CacheCapacity,
+    ttl: Option<Duration>,
+    weigher: fn(&K, &V) -> usize,
+    entries: HashMap<K, Entry<V>>,
+    /// Keys by the tick of their last access; the first entry is the least recently used one.
+    order: BTreeMap<u64, K>,
+    tick: u64,
+    bytes: usize,
+}
+
+impl<K: Hash + Eq + Clone, V> LruStore<K, V> {
+    pub(crate) fn new(capacity: CacheCapacity, ttl: Option<Duration>, weigher: fn(&K, &V) -> usize) -> Self {
+        LruStore {
+            capacity,
+            ttl,
+            weigher,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            bytes: 0,
+        }
+    }
+
+    fn is_full(&self, additional_bytes: usize) -> bool {
+        match self.capacity {
+            CacheCapacity::Entries(entries) => self.entries.len() >= entries,
+            CacheCapacity::Bytes(bytes) => self.bytes + additional_bytes > bytes,
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn is_expired(&self, entry: &Entry<V>) -> bool {
+        self.ttl.is_some_and(|ttl| entry.inserted.elapsed() >= ttl)
+    }
+
+    pub(crate) fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let entry = self.entries.get(key)?;
+        if self.is_expired(entry) {
+            self.remove(key);
+            return None;
+        }
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(key)?;
+        let key = self.order.remove(&entry.tick)?;
+        entry.tick = tick;
+        self.order.insert(tick, key);
+        Some(&entry.value)
+    }
+
+    /// Inserts the entry, evicting the least recently used ones to make room. Returns the number of
+    /// evicted entries.
+    ///
+    /// An entry that doesn't fit into an empty store isn't inserted at all.
+    pub(crate) fn insert(&mut self, key: K, value: V) -> usize {
+        let bytes = (self.weigher)(&key, &value);
+        let fits = match self.capacity {
+            CacheCapacity::Entries(entries) => entries > 0,
+            CacheCapacity::Bytes(budget) => bytes <= budget,
+        };
+        self.remove(&key);
+        if !fits {
+            return 0;
+        }
+        let mut evicted = 0;
+        while self.is_full(bytes) {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.bytes -= entry.bytes;
+                evicted += 1;
+            }
+        }
+        let tick = self.next_tick();
+        self.order.insert(tick, key.clone());
+        self.entries.insert(key, Entry { value, tick, inserted: Instant::now(), bytes });
+        self.bytes += bytes;
+        evicted
+    }
+
+    pub(crate) fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.tick);
+        self.bytes -= entry.bytes;
+        Some(entry.value)
+    }
+
+    /// Removes every entry whose key doesn't satisfy the predicate.
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(&K) -> bool) {
+        let (order, bytes) = (&mut self.order, &mut self.bytes);
+        self.entries.retain(|key, entry| {
+            let retained = keep(key);
+            if !retained {
+                order.remove(&entry.tick);
+                *bytes -= entry.bytes;
+            }
+            retained
+        });
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.bytes = 0;
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The total weight of all entries.
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(capacity: usize, ttl: Option<Duration>) -> LruStore<&'static str, i32> {
+        LruStore::new(CacheCapacity::Entries(capacity), ttl, |key, _| key.len())
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut store = store(2, None);
+        store.insert("a", 1);
+        store.insert("b", 2);
+        assert_eq!(store.get(&"a"), Some(&1));
+        store.insert("c", 3);
+        assert_eq!(store.get(&"b"), None);
+        assert_eq!(store.get(&"a"), Some(&1));
+        assert_eq!(store.get(&"c"), Some(&3));
+        assert_eq!(store.len(), 2);
+    }
+
+    #[test]
+    fn replacing_a_key_does_not_evict() {
+        let mut store = store(2, None);
+        store.insert("a", 1);
+        store.insert("b", 2);
+        store.insert("a", 3);
+        assert_eq!(store.get(&"a"), Some(&3));
+        assert_eq!(store.get(&"b"), Some(&2));
+    }
+
+    #[test]
+    fn expired_entries_are_dropped() {
+        let mut store = store(2, Some(Duration::ZERO));
+        store.insert("a", 1);
+        assert_eq!(store.get(&"a"), None);
+        assert_eq!(store.len(), 0);
+    }
+
+    #[test]
+    fn retain_keeps_order_consistent() {
+        let mut store = store(3, None);
+        store.insert("a", 1);
+        store.insert("b", 2);
+        store.insert("c", 3);
+        store.retain(|key| *key != "a");
+        assert_eq!(store.len(), 2);
+        store.insert("d", 4);
+        store.insert("e", 5);
+        assert_eq!(store.get(&"b"), None);
+        assert_eq!(store.get(&"c"), Some(&3));
+    }
+
+    #[test]
+    fn zero_capacity_stores_nothing() {
+        let mut store = store(0, None);
+        store.insert("a", 1);
+        assert_eq!(store.get(&"a"), None);
+    }
+
+    #[test]
+    fn byte_budget_evicts_by_weight() {
+        let mut store = LruStore::new(CacheCapacity::Bytes(10), None, |key: &&str, _: &i32| key.len());
+        assert_eq!(store.insert("aaaa", 1), 0);
+        assert_eq!(store.insert("bbbb", 2), 0);
+        assert_eq!(store.bytes(), 8);
+        assert_eq!(store.insert("cccccc", 3), 1);
+        assert_eq!((store.len(), store.bytes()), (2, 10));
+        assert_eq!(store.get(&"aaaa"), None);
+        assert_eq!(store.insert("dddddddddd", 4), 2);
+        assert_eq!((store.len(), store.bytes()), (1, 10));
+        store.insert("dddd", 4);
+        store.retain(|key| *key != "dddd");
+        assert_eq!((store.len(), store.bytes()), (0, 0));
+    }
+
+    #[test]
+    fn oversized_entries_are_not_stored() {
+        let mut store = LruStore::new(CacheCapacity::Bytes(4), None, |key: &&str, _: &i32| key.len());
+        store.insert("a", 1);
+        assert_eq!(store.insert("too large", 2), 0);
+        assert_eq!(store.get(&"too large"), None);
+        assert_eq!(store.get(&"a"), Some(&1));
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
+// Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE_MIT or LICENSE_APACHE files.
+
+use std::borrow::Borrow;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use super::lru::LruStore;
+use super::{AuthzCacheStats, CacheCapacity};
+
+/// The smallest share of a byte budget given to one shard, so that large entries still fit.
+const MIN_SHARD_BYTES: usize = 64 * 1024;
+
+/// The number of shards used unless configured otherwise: a few per available core, so that
+/// concurrent lookups rarely contend for the same lock.
+pub(crate) fn default_shard_count() -> usize {
+    let parallelism = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    (parallelism * 4).next_power_of_two()
+}
+
+struct Shard<K, V> {
+    store: Mutex<LruStore<K, V>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<K: Hash + Eq + Clone, V> Shard<K, V> {
+    // A panic while holding the lock cannot leave the store inconsistent, so poisoning is ignored.
+    fn store(&self) -> MutexGuard<'_, LruStore<K, V>> {
+        self.store.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn stats(&self) -> AuthzCacheStats {
+        let store = self.store();
+        AuthzCacheStats::new(
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+            store.len(),
+        )
+        .with_bytes(store.bytes())
+    }
+}
+
+/// A bounded LRU map split into independently locked shards, selected by the hash of the key.
+pub(crate) struct ShardedStore<K, V> {
+    shards: Box<[Shard<K, V>]>,
+    capacity: CacheCapacity,
+    ttl: Option<Duration>,
+    weigher: fn(&K, &V) -> usize,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> ShardedStore<K, V> {
+    /// Splits `capacity` over (at most) `shards` shards; every shard holds at least one entry, or
+    /// at least [`MIN_SHARD_BYTES`] of a byte budget.
+    pub(crate) fn new(capacity: CacheCapacity, ttl: Option<Duration>, shards: usize, weigher: fn(&K, &V) -> usize) -> Self {
+        let (limit, max_shards): (usize, usize) = match capacity {
+            CacheCapacity::Entries(entries) => (entries, entries),
+            CacheCapacity::Bytes(bytes) => (bytes, bytes / MIN_SHARD_BYTES),
+        };
+        let count = shards.clamp(1, max_shards.max(1));
+        let shards = (0..count)
+            .map(|i| {
+                let share = limit / count + usize::from(i < limit % count);
+                let share = match capacity {
+                    CacheCapacity::Entries(_) => CacheCapacity::Entries(share),
+                    CacheCapacity::Bytes(_) => CacheCapacity::Bytes(share),
+                };
+                Shard {
+                    store: Mutex::new(LruStore::new(share, ttl, weigher)),
+                    hits: AtomicU64::new(0),
+                    misses: AtomicU64::new(0),
+                }
+            })
+            .collect();
+        ShardedStore { shards, capacity, ttl, weigher }
+    }
+
+    /// The same store, split into a different number of shards. Entries are not carried over.
+    pub(crate) fn with_shards(&self, shards: usize) -> Self {
+        Self::new(self.capacity, self.ttl, shards, self.weigher)
+    }
+
+    pub(crate) fn capacity(&self) -> CacheCapacity {
+        self.capacity
+    }
+
+    pub(crate) fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn shard<Q: Hash + ?Sized>(&self, key: &Q) -> &Shard<K, V> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[(hasher.finish() % self.shards.len() as u64) as usize]
+    }
+
+    /// Returns a copy of the cached value, counting a hit or a miss.
+    pub(crate) fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let shard = self.shard(key);
+        let value = shard.store().get(key).cloned();
+        match value {
+            Some(_) => shard.hits.fetch_add(1, Ordering::Relaxed),
+            None => shard.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        value
+    }
+
+    /// Inserts the entry, returning the number of entries evicted to make room for it.
+    pub(crate) fn insert(&self, key: K, value: V) -> usize {
+        self.shard(&key).store().insert(key, value)
+    }
+
+    pub(crate) fn remove<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard(key).store().remove(key);
+    }
+
+    /// Removes every entry whose key doesn't satisfy the predicate, locking one shard at a time.
+    pub(crate) fn retain(&self, keep: impl Fn(&K) -> bool) {
+        for shard in self.shards.iter() {
+            shard.store().retain(&keep);
+        }
+    }
+
+    pub(crate) fn clear(&self) {
+        for shard in self.shards.iter() {
+            shard.store().clear();
+        }
+    }
+
+    pub(crate) fn shard_stats(&self) -> Vec<AuthzCacheStats> {
+        self.shards.iter().map(Shard::stats).collect()
+    }
+
+    pub(crate) fn stats(&self) -> AuthzCacheStats {
+        self.shard_stats().into_iter().fold(AuthzCacheStats::new(0, 0, 0), |total, shard| {
+            AuthzCacheStats::new(total.hits + shard.hits, total.misses + shard.misses, total.size + shard.size)
+                .with_bytes(total.bytes + shard.bytes)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries_store(capacity: usize, shards: usize) -> ShardedStore<u32, u32> {
+        ShardedStore::new(CacheCapacity::Entries(capacity), None, shards, |_, _| 8)
+    }
+
+    #[test]
+    fn capacity_is_split_over_shards() {
+        let store = entries_store(10, 4);
+        assert_eq!(store.shard_count(), 4);
+        for i in 0..1000 {
+            store.insert(i, i);
+        }
+        let sizes: Vec<usize> = store.shard_stats().iter().map(|stats| stats.size).collect();
+        assert_eq!(sizes, [3, 3, 2, 2]);
+        assert_eq!(store.stats().size, 10);
+    }
+
+    #[test]
+    fn small_caches_use_fewer_shards() {
+        let store = entries_store(2, 64);
+        assert_eq!(store.shard_count(), 2);
+        let store = entries_store(0, 64);
+        assert_eq!(store.shard_count(), 1);
+    }
+
+    #[test]
+    fn stats_are_aggregated_over_shards() {
+        let store = entries_store(100, 8);
+        for i in 0..50 {
+            assert_eq!(store.get(&i), None);
+            store.insert(i, i);
+            assert_eq!(store.get(&i), Some(i));
+        }
+        let stats = store.stats();
+        assert_eq!((stats.hits, stats.misses, stats.size, stats.bytes), (50, 50, 50, 400));
+        assert_eq!(store.shard_stats().iter().map(|stats| stats.hits).sum::<u64>(), 50);
+    }
+
+    #[test]
+    fn byte_budgets_keep_a_minimum_per_shard() {
+        let store: ShardedStore<u32, u32> = ShardedStore::new(CacheCapacity::Bytes(3 * MIN_SHARD_BYTES), None, 64, |_, _| 8);
+        assert_eq!(store.shard_count(), 3);
+        let store: ShardedStore<u32, u32> = ShardedStore::new(CacheCapacity::Bytes(100), None, 64, |_, _| 8);
+        assert_eq!(store.shard_count(), 1);
+        for i in 0..100 {
+            store.insert(i, i);
+        }
+        assert_eq!(store.stats().bytes, 96);
+    }
+}
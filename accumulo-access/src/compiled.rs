@@ -0,0 +1,352 @@
+// Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
+// Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE_MIT or LICENSE_APACHE files.
+
+use std::collections::HashMap;
+
+use crate::authorization_expression::AuthorizationExpression;
+use crate::authorizations::Authorizations;
+use crate::binary::{DecodeError, EncodedExpression, Encoder};
+use crate::error::InvalidTreeReason;
+use crate::json::check_label;
+
+/// `LabelDictionary` interns access tokens (labels) to dense `u32` identifiers.
+///
+/// Expressions compiled and authorization bitmaps built with the same dictionary can be evaluated
+/// against each other without hashing any strings.
+#[derive(Debug, Default, Clone)]
+pub struct LabelDictionary {
+    ids: HashMap<String, u32>,
+    labels: Vec<String>,
+}
+
+impl LabelDictionary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the identifier of the label, assigning the next free one if it hasn't been seen before.
+    pub fn intern(&mut self, label: &str) -> u32 {
+        if let Some(id) = self.ids.get(label) {
+            return *id;
+        }
+        let id = u32::try_from(self.labels.len()).expect("more than u32::MAX distinct labels");
+        self.ids.insert(label.to_string(), id);
+        self.labels.push(label.to_string());
+        id
+    }
+
+    /// Returns the identifier of the label, if it has been interned.
+    pub fn get(&self, label: &str) -> Option<u32> {
+        self.ids.get(label).copied()
+    }
+
+    /// Returns the label with the given identifier.
+    pub fn label(&self, id: u32) -> Option<&str> {
+        self.labels.get(id as usize).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// Compiles an expression, interning all the labels it references.
+    ///
+    /// # Example
+    /// ```
+    /// use accumulo_access::{LabelDictionary, Lexer, Parser};
+    /// let mut dictionary = LabelDictionary::new();
+    /// let expr = Parser::new(Lexer::new("A&(B|C)")).parse().unwrap();
+    /// let compiled = dictionary.compile(&expr);
+    ///
+    /// let authorizations = dictionary.bitmap(["A", "C"]);
+    /// assert!(compiled.evaluate(&authorizations));
+    /// ```
+    pub fn compile(&mut self, expr: &AuthorizationExpression) -> CompiledExpression {
+        let mut ops = Vec::new();
+        self.compile_into(expr, &mut ops);
+        CompiledExpression { ops }
+    }
+
+    fn compile_into(&mut self, expr: &AuthorizationExpression, ops: &mut Vec<Op>) {
+        let nodes: &[AuthorizationExpression] = match expr {
+            AuthorizationExpression::AccessToken(token) => {
+                let id = self.intern(token);
+                ops.push(Op::Label(id));
+                return;
+            }
+            AuthorizationExpression::Nil => &[],
+            AuthorizationExpression::ConjunctionOf(nodes) | AuthorizationExpression::DisjunctionOf(nodes) => nodes,
+        };
+        let position = ops.len();
+        ops.push(Op::All { end: 0 });
+        for node in nodes {
+            self.compile_into(node, ops);
+        }
+        let end = u32::try_from(ops.len()).expect("expression too large to compile");
+        ops[position] = match expr {
+            AuthorizationExpression::DisjunctionOf(_) => Op::Any { end },
+            _ => Op::All { end },
+        };
+    }
+
+    /// Decodes an expression in the binary format of [`AuthorizationExpression::encode`] and compiles
+    /// it, interning its labels.
+    pub fn decode(&mut self, bytes: &[u8]) -> Result<CompiledExpression, DecodeError> {
+        Ok(EncodedExpression::new(bytes)?.compile(self))
+    }
+
+    /// Builds the bitmap of the given access tokens.
+    ///
+    /// Tokens that have not been interned are ignored, as no expression compiled with this dictionary
+    /// references them. Rebuild the bitmap if expressions with new labels are compiled later on.
+    pub fn bitmap<I, S>(&self, tokens: I) -> AuthorizationBitmap
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut bitmap = AuthorizationBitmap::with_capacity(self.len());
+        for token in tokens {
+            if let Some(id) = self.get(token.as_ref()) {
+                bitmap.insert(id);
+            }
+        }
+        bitmap
+    }
+
+    /// Builds the bitmap of the given authorizations; see [`LabelDictionary::bitmap`].
+    pub fn authorizations_bitmap(&self, authorizations: &Authorizations) -> AuthorizationBitmap {
+        self.bitmap(authorizations.iter())
+    }
+}
+
+/// A set of label identifiers, stored as a bitmap.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct AuthorizationBitmap {
+    words: Vec<u64>,
+}
+
+impl AuthorizationBitmap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an empty bitmap with room for labels `0..labels` without reallocating.
+    pub fn with_capacity(labels: usize) -> Self {
+        AuthorizationBitmap {
+            words: Vec::with_capacity(labels.div_ceil(64)),
+        }
+    }
+
+    pub fn insert(&mut self, id: u32) {
+        let word = id as usize / 64;
+        if word >= self.words.len() {
+            self.words.resize(word + 1, 0);
+        }
+        self.words[word] |= 1 << (id % 64);
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        if let Some(word) = self.words.get_mut(id as usize / 64) {
+            *word &= !(1 << (id % 64));
+        }
+    }
+
+    #[inline]
+    pub fn contains(&self, id: u32) -> bool {
+        self.words
+            .get(id as usize / 64)
+            .is_some_and(|word| word & (1 << (id % 64)) != 0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Label(u32),
+    /// A conjunction whose operands are stored up to (excluding) `end`.
+    All { end: u32 },
+    /// A disjunction whose operands are stored up to (excluding) `end`.
+    Any { end: u32 },
+}
+
+/// An expression compiled against a [`LabelDictionary`].
+///
+/// The expression tree is stored as a flat, pre-order list of operations where every scope knows
+/// where it ends, so evaluation short-circuits without allocating.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompiledExpression {
+    ops: Vec<Op>,
+}
+
+impl CompiledExpression {
+    /// Evaluate the compiled expression with the given bitmap of authorizations.
+    /// Returns `true` if the authorizations are valid, `false` otherwise.
+    pub fn evaluate(&self, authorizations: &AuthorizationBitmap) -> bool {
+        self.evaluate_at(0, authorizations)
+    }
+
+    fn evaluate_at(&self, position: usize, authorizations: &AuthorizationBitmap) -> bool {
+        let (end, any) = match self.ops[position] {
+            Op::Label(id) => return authorizations.contains(id),
+            Op::All { end } => (end as usize, false),
+            Op::Any { end } => (end as usize, true),
+        };
+        let mut child = position + 1;
+        while child < end {
+            if self.evaluate_at(child, authorizations) == any {
+                return any;
+            }
+            child = self.next_sibling(child);
+        }
+        !any
+    }
+
+    /// Encodes the compiled expression in the binary format of [`AuthorizationExpression::encode`].
+    ///
+    /// The encoding carries its own label table, so it can be decoded with any dictionary using
+    /// [`LabelDictionary::decode`]. Like [`AuthorizationExpression::encode`], it fails for expressions
+    /// compiled from trees the format can't hold: with empty or invalid labels, empty disjunctions or
+    /// a nested empty expression.
+    ///
+    /// # Panics
+    /// If the expression was not compiled with the given dictionary.
+    ///
+    /// # Example
+    /// ```
+    /// use accumulo_access::{LabelDictionary, Lexer, Parser};
+    /// let mut dictionary = LabelDictionary::new();
+    /// let compiled = dictionary.compile(&Parser::new(Lexer::new("A&(B|C)")).parse().unwrap());
+    /// let bytes = compiled.encode(&dictionary).unwrap();
+    ///
+    /// let mut edge_dictionary = LabelDictionary::new();
+    /// let loaded = edge_dictionary.decode(&bytes).unwrap();
+    /// assert!(loaded.evaluate(&edge_dictionary.bitmap(["A", "B"])));
+    /// ```
+    pub fn encode(&self, dictionary: &LabelDictionary) -> Result<Vec<u8>, InvalidTreeReason> {
+        let mut encoder = Encoder::default();
+        self.encode_at(0, dictionary, &mut encoder)?;
+        Ok(encoder.finish())
+    }
+
+    fn encode_at<'a>(&self, position: usize, dictionary: &'a LabelDictionary, encoder: &mut Encoder<'a>) -> Result<(), InvalidTreeReason> {
+        let (end, any) = match self.ops[position] {
+            Op::Label(id) => {
+                let label = dictionary.label(id).expect("expression compiled with another dictionary");
+                check_label(label)?;
+                encoder.label(label);
+                return Ok(());
+            }
+            Op::All { end } => (end as usize, false),
+            Op::Any { end } => (end as usize, true),
+        };
+        // An empty conjunction is the empty expression, which is only allowed as the whole expression.
+        match (any, end == position + 1) {
+            (true, true) => return Err(InvalidTreeReason::NoOperands),
+            (false, true) if position != 0 => return Err(InvalidTreeReason::NestedNull),
+            _ => {}
+        }
+        let mut child = position + 1;
+        let mut arity = 0;
+        while child < end {
+            self.encode_at(child, dictionary, encoder)?;
+            child = self.next_sibling(child);
+            arity += 1;
+        }
+        encoder.operator(any, arity);
+        Ok(())
+    }
+
+    fn next_sibling(&self, position: usize) -> usize {
+        match self.ops[position] {
+            Op::Label(_) => position + 1,
+            Op::All { end } | Op::Any { end } => end as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Lexer, Parser};
+    use rstest::rstest;
+    use std::collections::HashSet;
+
+    #[test]
+    fn intern_assigns_dense_ids() {
+        let mut dictionary = LabelDictionary::new();
+        assert_eq!(dictionary.intern("A"), 0);
+        assert_eq!(dictionary.intern("B"), 1);
+        assert_eq!(dictionary.intern("A"), 0);
+        assert_eq!(dictionary.get("B"), Some(1));
+        assert_eq!(dictionary.get("C"), None);
+        assert_eq!(dictionary.label(1), Some("B"));
+        assert_eq!(dictionary.len(), 2);
+    }
+
+    #[test]
+    fn bitmap_grows_beyond_one_word() {
+        let mut bitmap = AuthorizationBitmap::new();
+        bitmap.insert(3);
+        bitmap.insert(200);
+        assert!(bitmap.contains(3));
+        assert!(bitmap.contains(200));
+        assert!(!bitmap.contains(199));
+        assert!(!bitmap.contains(10_000));
+        bitmap.remove(200);
+        assert!(!bitmap.contains(200));
+    }
+
+    // Evaluates every subset of the referenced labels (plus an unrelated one) with both evaluators.
+    #[rstest]
+    #[case("")]
+    #[case("A")]
+    #[case("A&B")]
+    #[case("A|B")]
+    #[case("A&(B|C)")]
+    #[case("(A|B)&(C|D)&E")]
+    #[case("(A&(B|(C&(D|E))))|(F&\"g h\")")]
+    #[case("((A|B))&(((C)))")]
+    fn compiled_evaluation_matches_evaluate(#[case] expression: &str) {
+        let expr = Parser::new(Lexer::new(expression)).parse().unwrap();
+        let mut dictionary = LabelDictionary::new();
+        let compiled = dictionary.compile(&expr);
+
+        let mut labels: Vec<String> = (0..dictionary.len() as u32)
+            .map(|id| dictionary.label(id).unwrap().to_string())
+            .collect();
+        labels.push("unrelated".to_string());
+
+        for mask in 0..(1u32 << labels.len()) {
+            let tokens: HashSet<String> = labels.iter()
+                .enumerate()
+                .filter(|(i, _)| mask & (1 << i) != 0)
+                .map(|(_, label)| label.clone())
+                .collect();
+            let bitmap = dictionary.bitmap(&tokens);
+            assert_eq!(compiled.evaluate(&bitmap), expr.evaluate(&tokens), "{} with {:?}", expression, tokens);
+        }
+    }
+
+    #[test]
+    fn empty_scopes_follow_evaluate() {
+        let mut dictionary = LabelDictionary::new();
+        let bitmap = AuthorizationBitmap::new();
+        assert!(dictionary.compile(&AuthorizationExpression::ConjunctionOf(vec![])).evaluate(&bitmap));
+        assert!(!dictionary.compile(&AuthorizationExpression::DisjunctionOf(vec![])).evaluate(&bitmap));
+    }
+
+    #[test]
+    fn shared_dictionary_across_expressions() {
+        let mut dictionary = LabelDictionary::new();
+        let a = dictionary.compile(&Parser::new(Lexer::new("A&B")).parse().unwrap());
+        let b = dictionary.compile(&Parser::new(Lexer::new("B|C")).parse().unwrap());
+        assert_eq!(dictionary.len(), 3);
+
+        let authorizations = dictionary.authorizations_bitmap(&Authorizations::of(&["B".to_string()]));
+        assert!(!a.evaluate(&authorizations));
+        assert!(b.evaluate(&authorizations));
+    }
+}
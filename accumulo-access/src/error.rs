@@ -0,0 +1,150 @@
+// Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
+// Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE_MIT or LICENSE_APACHE files.
+
+use thiserror::Error;
+
+use crate::binary::DecodeError;
+use crate::lexer::LexerError;
+use crate::normal_form::NormalFormError;
+use crate::parser::ParserError;
+
+/// `JsonError` is returned when an expression tree cannot be read from, or written to, JSON.
+#[derive(Error, Debug, PartialEq, Clone)]
+pub enum JsonError {
+    /// The input is not valid JSON.
+    ParsingFailed(String),
+    /// The JSON value is not a valid expression tree.
+    InvalidTree {
+        /// The JSON pointer (RFC 6901) to the offending node; empty for the root.
+        pointer: String,
+        reason: InvalidTreeReason,
+    },
+}
+
+impl std::fmt::Display for JsonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            JsonError::ParsingFailed(e) => write!(f, "{}", e),
+            JsonError::InvalidTree { pointer, reason } if pointer.is_empty() => write!(f, "Invalid expression tree at the root: {}", reason),
+            JsonError::InvalidTree { pointer, reason } => write!(f, "Invalid expression tree at {}: {}", pointer, reason),
+        }
+    }
+}
+
+/// Why a value is not a valid expression tree, in JSON or any other tree format read by the crate.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum InvalidTreeReason {
+    /// The node is neither a label (string), an `{"and": [...]}` / `{"or": [...]}` object nor, at the root, `null`.
+    UnexpectedValue(&'static str),
+    /// `null` (the empty expression) is only valid as the whole tree.
+    NestedNull,
+    /// A label is the empty string.
+    EmptyLabel,
+    /// A label contains a character that cannot be written in an expression, i.e. a control character.
+    InvalidCharacter(char),
+    /// The object has neither an `"and"` nor an `"or"` key.
+    MissingOperator,
+    /// The object has both an `"and"` and an `"or"` key.
+    AmbiguousOperator,
+    /// The object has a key other than `"and"` or `"or"`.
+    UnknownKey(String),
+    /// The operands of `"and"` / `"or"` are not an array.
+    OperandsNotAnArray,
+    /// The operands of `"and"` / `"or"` are an empty array.
+    NoOperands,
+}
+
+impl std::fmt::Display for InvalidTreeReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            InvalidTreeReason::UnexpectedValue(kind) => write!(f, "expected a label or an operator object, found {}", kind),
+            InvalidTreeReason::NestedNull => write!(f, "null is only allowed as the whole expression"),
+            InvalidTreeReason::EmptyLabel => write!(f, "labels must not be empty"),
+            InvalidTreeReason::InvalidCharacter(c) => write!(f, "labels must not contain control characters, found '{}'", c.escape_debug()),
+            InvalidTreeReason::MissingOperator => write!(f, "expected an \"and\" or \"or\" key"),
+            InvalidTreeReason::AmbiguousOperator => write!(f, "expected either \"and\" or \"or\", found both"),
+            InvalidTreeReason::UnknownKey(key) => write!(f, "unexpected key \"{}\"", key),
+            InvalidTreeReason::OperandsNotAnArray => write!(f, "expected an array of operands"),
+            InvalidTreeReason::NoOperands => write!(f, "expected at least one operand"),
+        }
+    }
+}
+
+/// `AccessError` is the error type of the crate's fallible operations, wrapping the more specific
+/// errors as its source.
+///
+/// The checking and conversion functions (e.g. [`check_authorization`](crate::check_authorization),
+/// the `caching` functions and [`AuthorizationExpression::from_json`](crate::AuthorizationExpression::from_json))
+/// return it. The conversion traits (`FromStr`, `TryFrom`), [`Parser::parse`](crate::Parser::parse)
+/// and the binary decoders keep the specific error of the one format they read. Every specific
+/// error converts into it, so `?` works across the whole API:
+///
+/// # Example
+/// ```
+/// use std::error::Error;
+/// use accumulo_access::{check_authorization, expression_to_json, AccessError, ParserError};
+///
+/// fn check(expression: &str) -> Result<bool, AccessError> {
+///     let json = expression_to_json(expression)?;
+///     assert!(json.is_object());
+///     Ok(check_authorization(expression, &["A".to_string()])?)
+/// }
+///
+/// assert_eq!(check("A|B"), Ok(true));
+/// let error = check("A&B|C").unwrap_err();
+/// assert_eq!(error, AccessError::Parse(ParserError::MixingOperators));
+/// assert_eq!(error.source().unwrap().to_string(), "Mixing operators");
+/// ```
+#[derive(Error, Debug, PartialEq, Clone)]
+pub enum AccessError {
+    /// The expression could not be parsed.
+    Parse(#[from] ParserError),
+    /// The expression could not be tokenized.
+    Lexer(#[from] LexerError),
+    /// An expression tree could not be read from, or written to, JSON.
+    Json(#[from] JsonError),
+    /// An expression could not be decoded from the binary format.
+    Decode(#[from] DecodeError),
+    /// An operation exceeded its configured limit.
+    Limit(#[from] NormalFormError),
+    /// A value is not valid, e.g. a label that cannot be used in an expression.
+    Validation(String),
+}
+
+impl std::fmt::Display for AccessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AccessError::Parse(e) => write!(f, "Invalid expression: {}", e),
+            AccessError::Lexer(e) => write!(f, "Invalid expression: {}", e),
+            AccessError::Json(e) => write!(f, "{}", e),
+            AccessError::Decode(e) => write!(f, "{}", e),
+            AccessError::Limit(e) => write!(f, "{}", e),
+            AccessError::Validation(e) => write!(f, "Validation failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn sources_are_chained() {
+        let error = AccessError::from(ParserError::LexerError(LexerError::UnexpectedCharacter('[', 1)));
+        assert_eq!(error.to_string(), "Invalid expression: Unexpected character '[' at position 1");
+        let parser_error = error.source().unwrap();
+        assert!(parser_error.downcast_ref::<ParserError>().is_some());
+        let lexer_error = parser_error.source().unwrap();
+        assert_eq!(lexer_error.downcast_ref::<LexerError>(), Some(&LexerError::UnexpectedCharacter('[', 1)));
+    }
+
+    #[test]
+    fn specific_errors_convert() {
+        assert_eq!(AccessError::from(NormalFormError::LimitExceeded(3)).to_string(), "Normal form exceeds the limit of 3 clauses");
+        let error = JsonError::InvalidTree { pointer: "/and/1".to_string(), reason: InvalidTreeReason::NoOperands };
+        assert_eq!(error.to_string(), "Invalid expression tree at /and/1: expected at least one operand");
+        assert!(AccessError::from(error).source().is_some());
+        assert!(AccessError::Validation("x".to_string()).source().is_none());
+    }
+}
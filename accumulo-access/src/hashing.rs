@@ -0,0 +1,47 @@
+// Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
+// Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE_MIT or LICENSE_APACHE files.
+
+/// A 64-bit FNV-1a hasher whose output is stable across builds and platforms, unlike `DefaultHasher`.
+///
+/// Used for the fingerprints and hashes that end up in logs and audit trails.
+pub(crate) struct Fnv1a(u64);
+
+impl Fnv1a {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    /// Starts hashing with the (little-endian) bytes of the salt.
+    pub(crate) fn with_salt(salt: u64) -> Self {
+        let mut hasher = Fnv1a(Self::OFFSET_BASIS);
+        hasher.write(&salt.to_le_bytes());
+        hasher
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(Self::PRIME);
+        }
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(salt: u64, text: &str) -> u64 {
+        let mut hasher = Fnv1a::with_salt(salt);
+        hasher.write(text.as_bytes());
+        hasher.finish()
+    }
+
+    #[test]
+    fn hashes_are_stable() {
+        assert_eq!(hash(0, "A"), 0xe604_413a_248f_bb4c);
+        assert_eq!(hash(42, "secret"), 0x53ac_11ed_01ec_8287);
+        assert_ne!(hash(0, "A"), hash(1, "A"));
+    }
+}
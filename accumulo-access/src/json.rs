@@ -0,0 +1,183 @@
+// Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
+// Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE_MIT or LICENSE_APACHE files.
+
+//! Reading the JSON tree format produced by [`AuthorizationExpression::to_json`].
+
+use serde_json::Value;
+
+use crate::error::{InvalidTreeReason, JsonError};
+use crate::{is_valid_label, AuthorizationExpression};
+
+/// The JSON Schema (draft 2020-12) of the tree format, also published as `schema/expression-tree.schema.json`.
+pub(crate) const SCHEMA: &str = include_str!("../schema/expression-tree.schema.json");
+
+fn invalid(pointer: &str, reason: InvalidTreeReason) -> JsonError {
+    JsonError::InvalidTree {
+        pointer: pointer.to_string(),
+        reason,
+    }
+}
+
+fn value_kind(json: &Value) -> &'static str {
+    match json {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+/// Appends a reference token to a JSON pointer, escaping it as described in RFC 6901.
+fn child_pointer(pointer: &str, token: &str) -> String {
+    format!("{}/{}", pointer, token.replace('~', "~0").replace('/', "~1"))
+}
+
+/// Reads a whole expression tree: `null` for the empty expression, otherwise a node.
+pub(crate) fn read_tree(json: &Value) -> Result<AuthorizationExpression, JsonError> {
+    let expr = read_node(json, "")?;
+    validate_tree(&expr)?;
+    Ok(expr)
+}
+
+/// Reads the shape of a node; the rules of the tree itself are checked by [`validate_tree`].
+fn read_node(json: &Value, pointer: &str) -> Result<AuthorizationExpression, JsonError> {
+    match json {
+        Value::String(label) => Ok(AuthorizationExpression::AccessToken(label.clone())),
+        Value::Null => Ok(AuthorizationExpression::Nil),
+        Value::Object(object) => {
+            if let Some(key) = object.keys().find(|key| *key != "and" && *key != "or") {
+                return Err(invalid(pointer, InvalidTreeReason::UnknownKey(key.clone())));
+            }
+            let (key, operands) = match (object.get("and"), object.get("or")) {
+                (Some(operands), None) => ("and", operands),
+                (None, Some(operands)) => ("or", operands),
+                (Some(_), Some(_)) => return Err(invalid(pointer, InvalidTreeReason::AmbiguousOperator)),
+                (None, None) => return Err(invalid(pointer, InvalidTreeReason::MissingOperator)),
+            };
+            let pointer = child_pointer(pointer, key);
+            let Value::Array(operands) = operands else {
+                return Err(invalid(&pointer, InvalidTreeReason::OperandsNotAnArray));
+            };
+            let nodes = operands
+                .iter()
+                .enumerate()
+                .map(|(index, operand)| read_node(operand, &child_pointer(&pointer, &index.to_string())))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(match key {
+                "and" => AuthorizationExpression::ConjunctionOf(nodes),
+                _ => AuthorizationExpression::DisjunctionOf(nodes),
+            })
+        }
+        _ => Err(invalid(pointer, InvalidTreeReason::UnexpectedValue(value_kind(json)))),
+    }
+}
+
+/// Checks the rules every expression tree must follow, whichever format it was read from: the empty
+/// expression only as the whole tree, at least one operand per operator, and only labels that can be
+/// written in an expression. The pointer of the error follows the JSON tree format (`/and/1/or/0`).
+pub(crate) fn validate_tree(expr: &AuthorizationExpression) -> Result<(), JsonError> {
+    if *expr == AuthorizationExpression::Nil {
+        return Ok(());
+    }
+    validate_node(expr).map_err(|(path, reason)| {
+        let pointer = path.iter().rev().fold(String::new(), |pointer, token| child_pointer(&pointer, token));
+        invalid(&pointer, reason)
+    })
+}
+
+/// Checks a node. The path to an invalid node is collected on the way back up, so valid trees don't
+/// pay for building pointers.
+fn validate_node(expr: &AuthorizationExpression) -> Result<(), (Vec<String>, InvalidTreeReason)> {
+    let (key, nodes) = match expr {
+        AuthorizationExpression::Nil => return Err((Vec::new(), InvalidTreeReason::NestedNull)),
+        AuthorizationExpression::AccessToken(label) => return check_label(label).map_err(|reason| (Vec::new(), reason)),
+        AuthorizationExpression::ConjunctionOf(nodes) => ("and", nodes),
+        AuthorizationExpression::DisjunctionOf(nodes) => ("or", nodes),
+    };
+    if nodes.is_empty() {
+        return Err((vec![key.to_string()], InvalidTreeReason::NoOperands));
+    }
+    for (index, node) in nodes.iter().enumerate() {
+        validate_node(node).map_err(|(mut path, reason)| {
+            path.push(index.to_string());
+            path.push(key.to_string());
+            (path, reason)
+        })?;
+    }
+    Ok(())
+}
+
+pub(crate) fn check_label(label: &str) -> Result<(), InvalidTreeReason> {
+    if is_valid_label(label) {
+        return Ok(());
+    }
+    match label.chars().find(|c| !is_valid_label(c.encode_utf8(&mut [0; 4]))) {
+        Some(c) => Err(InvalidTreeReason::InvalidCharacter(c)),
+        None => Err(InvalidTreeReason::EmptyLabel),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Lexer, Parser};
+    use rstest::rstest;
+    use serde_json::json;
+
+    fn schema_accepts(json: &Value) -> bool {
+        jsonschema::draft202012::new(&AuthorizationExpression::json_schema()).unwrap().is_valid(json)
+    }
+
+    #[rstest]
+    #[case(json!({"and": "A"}), "/and", InvalidTreeReason::OperandsNotAnArray)]
+    #[case(json!({"and": ["A"], "or": ["B"]}), "", InvalidTreeReason::AmbiguousOperator)]
+    #[case(json!({"and": ["A"], "comment": "x"}), "", InvalidTreeReason::UnknownKey("comment".to_string()))]
+    #[case(json!({}), "", InvalidTreeReason::MissingOperator)]
+    #[case(json!({"or": []}), "/or", InvalidTreeReason::NoOperands)]
+    #[case(json!({"and": ["A", {"or": ["B", 7]}]}), "/and/1/or/1", InvalidTreeReason::UnexpectedValue("a number"))]
+    #[case(json!({"and": ["A", null]}), "/and/1", InvalidTreeReason::NestedNull)]
+    #[case(json!({"or": ["A", ""]}), "/or/1", InvalidTreeReason::EmptyLabel)]
+    #[case(json!({"and": ["A", {"or": ["B", "a\nb"]}]}), "/and/1/or/1", InvalidTreeReason::InvalidCharacter('\n'))]
+    #[case(json!("del\u{7f}"), "", InvalidTreeReason::InvalidCharacter('\u{7f}'))]
+    #[case(json!(["A", "B"]), "", InvalidTreeReason::UnexpectedValue("an array"))]
+    #[case(json!(true), "", InvalidTreeReason::UnexpectedValue("a boolean"))]
+    fn invalid_trees_are_rejected_with_a_pointer(#[case] json: Value, #[case] pointer: &str, #[case] reason: InvalidTreeReason) {
+        assert_eq!(read_tree(&json), Err(invalid(pointer, reason)));
+        assert!(!schema_accepts(&json), "the schema accepts {}", json);
+    }
+
+    #[test]
+    fn schema_declares_draft_2020_12() {
+        assert_eq!(AuthorizationExpression::json_schema()["$schema"], "https://json-schema.org/draft/2020-12/schema");
+    }
+
+    #[rstest]
+    #[case("label 🕺", true)]
+    #[case("say \"hi\" \\o/", true)]
+    #[case("new\nline", false)]
+    #[case("nul\0", false)]
+    #[case("del\u{7f}", false)]
+    fn schema_rejects_control_characters_in_labels(#[case] label: &str, #[case] valid: bool) {
+        assert_eq!(schema_accepts(&json!({"and": ["A", label]})), valid);
+    }
+
+    #[test]
+    fn pointer_tokens_are_escaped() {
+        assert_eq!(child_pointer("/and", "a/b~c"), "/and/a~1b~0c");
+    }
+
+    #[rstest]
+    #[case("")]
+    #[case("A")]
+    #[case("A&B")]
+    #[case("A|(B&\"c d\")")]
+    #[case("(A|B)&(C|(D&\"e\\\"f\"))")]
+    fn to_json_round_trips(#[case] expression: &str) {
+        let expr = Parser::new(Lexer::new(expression)).parse().unwrap();
+        assert!(schema_accepts(&expr.to_json()), "the schema rejects {}", expr.to_json());
+        assert_eq!(read_tree(&expr.to_json()), Ok(expr.clone()));
+        assert_eq!(AuthorizationExpression::from_json_str(&expr.to_json_str()), Ok(expr));
+    }
+}
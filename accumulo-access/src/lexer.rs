@@ -1,9 +1,8 @@
 // Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
 // Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE_MIT or LICENSE_APACHE files.
 
+use std::borrow::Cow;
 use std::fmt::Display;
-use std::iter::Peekable;
-use std::str::Chars;
 use thiserror::Error;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -36,13 +35,16 @@ impl Display for Token {
 /// `Lexer` is a lexical analyzer (tokenizer) for authorization expressions.
 #[derive(Debug, Clone)]
 pub struct Lexer<'a> {
-    inner_peekable_iterator: Peekable<Chars<'a>>,
-    position: usize,
+    /// Reads the characters, and the labels, of the input.
+    scanner: LabelScanner<'a>,
 }
 
 #[derive(Error, Debug, PartialEq, Clone)]
+#[non_exhaustive]
 pub enum LexerError {
     UnexpectedCharacter(char, usize),
+    /// The input ended before the token was complete, e.g. in a quoted label.
+    UnexpectedEnd(usize),
 }
 
 impl Display for LexerError {
@@ -51,6 +53,7 @@ impl Display for LexerError {
             LexerError::UnexpectedCharacter(c, position) => {
                 write!(f, "Unexpected character '{}' at position {}", c, position)
             }
+            LexerError::UnexpectedEnd(position) => write!(f, "Unexpected end of input at position {}", position),
         }
     }
 }
@@ -62,23 +65,20 @@ impl<'a> Lexer<'a> {
     ///
     /// * `input` - The authorization expression to tokenize.
     pub fn new(input: &'a str) -> Self {
-        let inner_peekable_iterator = input.chars().peekable();
         Lexer {
-            inner_peekable_iterator,
-            position: 0,
+            scanner: LabelScanner::new(input),
         }
     }
 
-    fn read_char(&mut self) -> Option<char> {
-        let c = self.inner_peekable_iterator.next();
-        if c.is_some() {
-            self.position += 1;
-        }
-        c
+    /// The whole input of the lexer, regardless of how much has been tokenized.
+    #[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+    pub(crate) fn input(&self) -> &'a str {
+        self.scanner.input
     }
 
-    fn peek_char(&mut self) -> Option<&char> {
-        self.inner_peekable_iterator.peek()
+    /// The number of characters tokenized so far.
+    pub(crate) fn position(&self) -> usize {
+        self.scanner.position
     }
 }
 
@@ -91,7 +91,7 @@ fn is_allowed_char_for_unquoted_access_token(c: char) -> bool {
         || c == '/'
 }
 
-fn is_allowed_char_for_quoted_access_token(c: char) -> bool {
+const fn is_allowed_char_for_quoted_access_token(c: char) -> bool {
     // from SPECIFICATION.md:
     //
     // check that the character is in the valid ranges:
@@ -103,92 +103,270 @@ fn is_allowed_char_for_quoted_access_token(c: char) -> bool {
         || (c as u32) >= 0xE000 && (c as u32) <= 0x10FFFF
 }
 
-impl Iterator for Lexer<'_> {
-    type Item = Result<Token, LexerError>;
+/// Returns `true` if the label must be wrapped in quotes to be used in an expression, i.e. if it is
+/// empty or contains characters other than ASCII letters, digits, `_`, `-`, `.`, `:` and `/`.
+///
+/// # Example
+/// ```
+/// use accumulo_access::needs_quoting;
+/// assert!(!needs_quoting("project-alpha/2024"));
+/// assert!(needs_quoting("project alpha"));
+/// ```
+pub fn needs_quoting(label: &str) -> bool {
+    label.is_empty() || !label.chars().all(is_allowed_char_for_unquoted_access_token)
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let c = self.read_char()?;
-        let r = match c {
-            '(' => {
-                //self.read_char();
-                Ok(Token::OpenParen)
-            }
+/// Returns `true` if the label can be used in an expression (quoted, if [`needs_quoting`]): it must
+/// not be empty, and must not contain control characters.
+///
+/// # Example
+/// ```
+/// use accumulo_access::is_valid_label;
+/// assert!(is_valid_label("say \"hi\" 🕺"));
+/// assert!(!is_valid_label(""));
+/// assert!(!is_valid_label("tab\t"));
+/// ```
+pub const fn is_valid_label(label: &str) -> bool {
+    // Every character beyond ASCII is allowed, so only the ASCII bytes need to be checked, which
+    // keeps this usable in constants (e.g. by `access_expr!`).
+    let bytes = label.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i].is_ascii() && !is_allowed_char_for_quoted_access_token(bytes[i] as char) {
+            return false;
+        }
+        i += 1;
+    }
+    !bytes.is_empty()
+}
 
-            ')' => {
-                //self.read_char();
-                Ok(Token::CloseParen)
-            }
-            '&' => {
-                //self.read_char();
-                Ok(Token::And)
-            }
-            '|' => {
-                //self.read_char();
-                Ok(Token::Or)
-            }
-            '"' => {
-                self.handle_quoted_access_token()
-            }
-            _ if is_allowed_char_for_unquoted_access_token(c) => {
-                self.handle_unquoted_access_token(c)
-            }
-            _ => {
-                //self.read_char();
-                Err(LexerError::UnexpectedCharacter(c, self.position))
-            }
-        };
-        Some(r)
+/// Returns the label as it must be written in an expression, i.e. wrapped in quotes (with `"` and
+/// `\` escaped) if it [`needs_quoting`], and borrowed as it is otherwise.
+///
+/// Quoting doesn't make an invalid label valid; check labels from untrusted input with [`is_valid_label`].
+///
+/// # Example
+/// ```
+/// use accumulo_access::{quote, unquote};
+/// assert_eq!(quote("A"), "A");
+/// assert_eq!(quote("a \"b\""), "\"a \\\"b\\\"\"");
+/// assert_eq!(unquote(&quote("a \"b\"")).unwrap(), "a \"b\"");
+/// ```
+pub fn quote(label: &str) -> Cow<'_, str> {
+    if !needs_quoting(label) {
+        return Cow::Borrowed(label);
+    }
+    let mut quoted = String::with_capacity(label.len() + 2);
+    quoted.push('"');
+    for c in label.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    Cow::Owned(quoted)
+}
+
+/// Reads a single label as written in an expression, removing the quotes and escapes of a quoted
+/// label. The whole input must be one label. The result is borrowed unless there was something to
+/// unescape.
+///
+/// This is stricter than the `Lexer`, which accepts a quoted label without its closing quote at the
+/// end of an expression (`"open`); `unquote` rejects it with [`LexerError::UnexpectedEnd`], as the
+/// token is incomplete.
+///
+/// # Example
+/// ```
+/// use accumulo_access::{unquote, LexerError};
+/// assert_eq!(unquote("A").unwrap(), "A");
+/// assert_eq!(unquote("\"c \\\\ d\"").unwrap(), "c \\ d");
+/// assert_eq!(unquote("A&B"), Err(LexerError::UnexpectedCharacter('&', 2)));
+/// assert_eq!(unquote("\"open"), Err(LexerError::UnexpectedEnd(5)));
+/// ```
+pub fn unquote(token: &str) -> Result<Cow<'_, str>, LexerError> {
+    let mut scanner = LabelScanner::new(token);
+    let label = match scanner.read_char() {
+        None => return Err(LexerError::UnexpectedEnd(0)),
+        Some('"') => match scanner.quoted()? {
+            (label, true) => label,
+            (_, false) => return Err(LexerError::UnexpectedEnd(scanner.position)),
+        },
+        Some(c) if is_allowed_char_for_unquoted_access_token(c) => scanner.unquoted(0),
+        Some(c) => return Err(LexerError::UnexpectedCharacter(c, 1)),
+    };
+    match scanner.read_char() {
+        None => Ok(label),
+        Some(c) => Err(LexerError::UnexpectedCharacter(c, scanner.position)),
     }
 }
 
-impl Lexer<'_> {
-    fn handle_quoted_access_token(&mut self) -> Result<Token, LexerError> {
-        let mut value = String::new();
-        //self.read_char(); // discard the opening quote
+/// Calls `f` with every label in the expression, in the order they appear, without building a tree.
+///
+/// Quoted labels are unescaped; labels are borrowed from the expression unless they contain escapes,
+/// so scanning is allocation-free for most expressions. Labels referenced more than once are
+/// reported every time.
+///
+/// Only the tokens are validated, so the scan stops at the first character that is not allowed, but
+/// not at operators in the wrong place. Use [`AccessExpression::validate`](crate::AccessExpression::validate)
+/// to check the whole expression.
+///
+/// # Example
+/// ```
+/// use accumulo_access::for_each_label;
+///
+/// let mut labels = Vec::new();
+/// for_each_label("A&(\"b \\\"c\\\"\"|D)", |label| labels.push(label.into_owned())).unwrap();
+/// assert_eq!(labels, ["A", "b \"c\"", "D"]);
+/// ```
+pub fn for_each_label<'a, F>(expression: &'a str, mut f: F) -> Result<(), LexerError>
+where
+    F: FnMut(Cow<'a, str>),
+{
+    for label in LabelScanner::new(expression) {
+        f(label?);
+    }
+    Ok(())
+}
+
+/// Scans the labels of an expression, following the same rules (and reporting the same errors) as
+/// the `Lexer`, which reads its labels with one. Stops after the first error.
+#[derive(Debug, Clone)]
+pub(crate) struct LabelScanner<'a> {
+    input: &'a str,
+    /// Byte offset of the next character.
+    offset: usize,
+    /// Number of characters read, which is how errors report positions.
+    position: usize,
+    failed: bool,
+}
+
+impl<'a> LabelScanner<'a> {
+    pub(crate) fn new(input: &'a str) -> Self {
+        LabelScanner {
+            input,
+            offset: 0,
+            position: 0,
+            failed: false,
+        }
+    }
+
+    fn read_char(&mut self) -> Option<char> {
+        let c = self.input[self.offset..].chars().next()?;
+        self.offset += c.len_utf8();
+        self.position += 1;
+        Some(c)
+    }
+
+    fn fail(&mut self, c: char) -> LexerError {
+        self.failed = true;
+        LexerError::UnexpectedCharacter(c, self.position)
+    }
+
+    fn unquoted(&mut self, start: usize) -> Cow<'a, str> {
+        // The characters allowed unquoted are all ASCII, so the label can be scanned by bytes.
+        let len = self.input.as_bytes()[self.offset..]
+            .iter()
+            .take_while(|b| is_allowed_char_for_unquoted_access_token(**b as char))
+            .count();
+        self.offset += len;
+        self.position += len;
+        Cow::Borrowed(&self.input[start..self.offset])
+    }
+
+    /// Reads the rest of a quoted label, and whether its closing quote was found. Labels have at least
+    /// one character, so `""` is rejected at its closing quote.
+    fn quoted(&mut self) -> Result<(Cow<'a, str>, bool), LexerError> {
+        let start = self.offset;
+        let mut terminated = false;
+        let mut end = self.input.len();
+        // Only allocated once an escape sequence is found.
+        let mut unescaped: Option<String> = None;
         while let Some(c) = self.read_char() {
-            if !is_allowed_char_for_quoted_access_token(c)
-            {
-                return Err(LexerError::UnexpectedCharacter(c, self.position));
+            if !is_allowed_char_for_quoted_access_token(c) {
+                return Err(self.fail(c));
             }
             match c {
                 '\\' => {
-                    if let Some(next_char) = self.read_char() {
-                        if next_char == '"' || next_char == '\\' {
-                            value.push(next_char);
-                        } else {
-                            return Err(LexerError::UnexpectedCharacter(next_char, self.position));
+                    let escape = self.offset - 1;
+                    match self.read_char() {
+                        Some(next_char @ ('"' | '\\')) => {
+                            unescaped
+                                .get_or_insert_with(|| self.input[start..escape].to_string())
+                                .push(next_char);
                         }
+                        Some(next_char) => return Err(self.fail(next_char)),
+                        // A trailing backslash is dropped, like the `Lexer` does.
+                        None => end = escape,
                     }
                 }
                 '"' => {
+                    end = self.offset - 1;
+                    terminated = true;
                     break;
                 }
                 _ => {
-                    value.push(c);
+                    if let Some(unescaped) = &mut unescaped {
+                        unescaped.push(c);
+                    }
                 }
             }
         }
-        Ok(Token::AccessToken(value))
+        let label = unescaped.map_or(Cow::Borrowed(&self.input[start..end]), Cow::Owned);
+        match (label.is_empty(), terminated) {
+            (true, true) => Err(self.fail('"')),
+            (true, false) => {
+                self.failed = true;
+                Err(LexerError::UnexpectedEnd(self.position))
+            }
+            (false, _) => Ok((label, terminated)),
+        }
     }
+}
 
-    fn handle_unquoted_access_token(&mut self, first_char: char) -> Result<Token, LexerError> {
-        let mut value = String::new();
-        value.push(first_char);
-        while let Some(c) = self.peek_char() {
-            if is_allowed_char_for_unquoted_access_token(*c) {
-                let c = self.read_char().unwrap();
-                value.push(c);
-            } else {
-                break;
-            }
+impl<'a> Iterator for LabelScanner<'a> {
+    type Item = Result<Cow<'a, str>, LexerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        loop {
+            let start = self.offset;
+            let c = self.read_char()?;
+            return Some(match c {
+                '(' | ')' | '&' | '|' => continue,
+                '"' => self.quoted().map(|(label, _)| label),
+                _ if is_allowed_char_for_unquoted_access_token(c) => Ok(self.unquoted(start)),
+                _ => Err(self.fail(c)),
+            });
         }
-        Ok(Token::AccessToken(value))
+    }
+}
+
+impl Iterator for Lexer<'_> {
+    type Item = Result<Token, LexerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = self.scanner.offset;
+        let c = self.scanner.read_char()?;
+        let r = match c {
+            '(' => Ok(Token::OpenParen),
+            ')' => Ok(Token::CloseParen),
+            '&' => Ok(Token::And),
+            '|' => Ok(Token::Or),
+            '"' => self.scanner.quoted().map(|(label, _)| Token::AccessToken(label.into_owned())),
+            _ if is_allowed_char_for_unquoted_access_token(c) => Ok(Token::AccessToken(self.scanner.unquoted(start).into_owned())),
+            _ => Err(LexerError::UnexpectedCharacter(c, self.scanner.position)),
+        };
+        Some(r)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rstest::rstest;
 
     #[test]
     fn test_lexer_valid() {
@@ -237,6 +415,84 @@ mod tests {
             ]);
     }
 
+    #[test]
+    fn label_scanner_borrows_unless_unescaping() {
+        let labels: Vec<Cow<str>> = LabelScanner::new("abc&\"d e\"|\"f\\\\g\"")
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(labels, ["abc", "d e", "f\\g"]);
+        assert!(matches!(labels[0], Cow::Borrowed(_)));
+        assert!(matches!(labels[1], Cow::Borrowed(_)));
+        assert!(matches!(labels[2], Cow::Owned(_)));
+    }
+
+    // The scanner must report exactly the labels (and the first error) of the lexer.
+    #[rstest]
+    #[case("label1&\"label 🕺\"|(\"hello \\\\ \\\"world\"|label4|(label5&label6)))")]
+    #[case("\"abc!12\"&\"abc\\\\xyz\"&GHI")]
+    #[case("\"\"|\"unterminated")]
+    #[case("A&\"\"")]
+    #[case("A|\"")]
+    #[case("\"\\")]
+    #[case("\"é\\")]
+    #[case("A&\"b\\x\"")]
+    #[case("label1 & [")]
+    #[case("A&\"tab\tx\"")]
+    #[case("")]
+    fn label_scanner_matches_lexer(#[case] input: &str) {
+        let mut expected = Vec::new();
+        for token in Lexer::new(input) {
+            match token {
+                Ok(Token::AccessToken(label)) => expected.push(Ok(label)),
+                Ok(_) => {}
+                Err(error) => {
+                    expected.push(Err(error));
+                    break;
+                }
+            }
+        }
+        let scanned: Vec<_> = LabelScanner::new(input).map(|label| label.map(Cow::into_owned)).collect();
+        assert_eq!(scanned, expected);
+    }
+
+    #[rstest]
+    #[case("A", "A")]
+    #[case("a.b:c/d_e-f", "a.b:c/d_e-f")]
+    #[case("label 🕺", "\"label 🕺\"")]
+    #[case("say \"hi\" \\o/", "\"say \\\"hi\\\" \\\\o/\"")]
+    fn quote_round_trips(#[case] label: &str, #[case] quoted: &str) {
+        assert_eq!(quote(label), quoted);
+        assert_eq!(needs_quoting(label), label != quoted);
+        assert_eq!(unquote(quoted).unwrap(), label);
+        // What the lexer reads back is what was quoted.
+        assert_eq!(Lexer::new(quoted).collect::<Vec<_>>(), [Ok(Token::AccessToken(label.to_string()))]);
+    }
+
+    #[rstest]
+    #[case("", LexerError::UnexpectedEnd(0))]
+    #[case("\"\"", LexerError::UnexpectedCharacter('"', 2))]
+    #[case("\"", LexerError::UnexpectedEnd(1))]
+    #[case("\"abc", LexerError::UnexpectedEnd(4))]
+    #[case("\"a\\\"", LexerError::UnexpectedEnd(4))]
+    #[case("\"a\"b", LexerError::UnexpectedCharacter('b', 4))]
+    #[case("\"a\\b\"", LexerError::UnexpectedCharacter('b', 4))]
+    #[case("a b", LexerError::UnexpectedCharacter(' ', 2))]
+    #[case("(a)", LexerError::UnexpectedCharacter('(', 1))]
+    fn unquote_rejects_anything_but_one_label(#[case] token: &str, #[case] error: LexerError) {
+        assert_eq!(unquote(token), Err(error));
+    }
+
+    #[rstest]
+    #[case("A", true)]
+    #[case("with space", true)]
+    #[case("\"\\", true)]
+    #[case("", false)]
+    #[case("new\nline", false)]
+    #[case("del\u{7f}", false)]
+    fn valid_labels(#[case] label: &str, #[case] valid: bool) {
+        assert_eq!(is_valid_label(label), valid);
+    }
+
     #[test]
     fn test_lexer_invalid() {
         let input = "label1 & [";
@@ -5,29 +5,154 @@ mod lexer;
 mod parser;
 #[cfg(feature = "caching")]
 pub mod caching;
+#[cfg(feature = "bdd")]
+pub mod bdd;
+#[cfg(feature = "metrics")]
+pub mod telemetry;
+#[cfg(feature = "tracing")]
+pub mod trace;
+#[cfg(feature = "protobuf")]
+pub mod proto;
+pub mod audit;
+mod hashing;
+mod error;
+mod json;
+pub mod serialization;
 pub mod authorization_expression;
+mod access_expression;
 mod authorizations;
+mod analysis;
+mod minimize;
+mod normal_form;
+mod compiled;
+mod builder;
+mod binary;
 
-pub use crate::lexer::Lexer;
+pub use crate::lexer::{for_each_label, is_valid_label, needs_quoting, quote, unquote, Lexer, LexerError};
+pub use crate::error::{AccessError, InvalidTreeReason, JsonError};
 pub use crate::parser::Parser;
 pub use crate::parser::ParserError;
 pub use crate::authorizations::Authorizations;
 pub use crate::authorization_expression::AuthorizationExpression;
+pub use crate::access_expression::AccessExpression;
+pub use crate::builder::Expr;
+pub use crate::minimize::DEFAULT_EXACT_MINIMIZATION_THRESHOLD;
+pub use crate::compiled::{AuthorizationBitmap, CompiledExpression, LabelDictionary};
+pub use crate::binary::{DecodeError, EncodedExpression, BINARY_FORMAT_VERSION, MAX_DECODE_DEPTH};
+pub use crate::normal_form::{NormalForm, NormalFormError, NormalFormKind, DEFAULT_NORMAL_FORM_LIMIT};
+pub use crate::analysis::ANALYSIS_DNF_LIMIT;
 
-pub enum JsonError {
-    ParsingFailed(String),
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// `AccessEvaluator` checks expressions against a fixed set of authorizations, e.g. those of one user.
+///
+/// # Examples
+/// ```
+/// use accumulo_access::{AccessEvaluator, Authorizations};
+///
+/// let evaluator = AccessEvaluator::of(&Authorizations::of(&["label1".to_string()]));
+/// assert_eq!(evaluator.can_access("label1|label5"), Ok(true));
+/// assert_eq!(evaluator.can_access("label1&label5"), Ok(false));
+/// ```
+pub struct AccessEvaluator {
+    authorizations: HashSet<String>,
+    #[cfg(feature = "caching")]
+    expression_cache: Option<Arc<caching::ExpressionCache>>,
+    audit: Option<(Arc<dyn audit::AuditSink>, String)>,
 }
 
-// implement Display for JsonError 
-impl std::fmt::Display for JsonError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            JsonError::ParsingFailed(e) => write!(f, "{}", e),
+impl AccessEvaluator {
+    /// Creates a new `AccessEvaluator` for the given authorizations.
+    pub fn of(authorizations: &Authorizations) -> Self {
+        AccessEvaluator {
+            authorizations: authorizations.to_set(),
+            #[cfg(feature = "caching")]
+            expression_cache: None,
+            audit: None,
+        }
+    }
+
+    /// Parses expressions through the given cache, which may be shared with other evaluators.
+    ///
+    /// # Examples
+    /// ```
+    /// use std::sync::Arc;
+    /// use accumulo_access::{AccessEvaluator, Authorizations};
+    /// use accumulo_access::caching::ExpressionCache;
+    ///
+    /// let cache = Arc::new(ExpressionCache::new(1000));
+    /// let alice = AccessEvaluator::of(&Authorizations::of(&["A".to_string()])).with_expression_cache(cache.clone());
+    /// let bob = AccessEvaluator::of(&Authorizations::of(&["B".to_string()])).with_expression_cache(cache.clone());
+    /// assert_eq!(alice.can_access("A&B"), Ok(false));
+    /// assert_eq!(bob.can_access("A|B"), Ok(true));
+    /// assert_eq!(bob.can_access("A&B"), Ok(false));
+    /// assert_eq!(cache.stats().hits, 1);
+    /// ```
+    #[cfg(feature = "caching")]
+    pub fn with_expression_cache(mut self, cache: Arc<caching::ExpressionCache>) -> Self {
+        self.expression_cache = Some(cache);
+        self
+    }
+
+    /// Records every decision of this evaluator in the given sink, on behalf of `principal`.
+    ///
+    /// Without a sink of its own, the evaluator records its decisions in the process-wide sink, if
+    /// one is installed with [`audit::set_audit_sink`].
+    pub fn with_audit_sink(mut self, sink: Arc<dyn audit::AuditSink>, principal: impl Into<String>) -> Self {
+        self.audit = Some((sink, principal.into()));
+        self
+    }
+
+    /// Checks if the authorizations grant access to the resource which protection is described by the given expression.
+    ///
+    /// Returns:
+    /// * `Ok(true)` if the expression is valid and the authorizations are sufficient.
+    /// * `Ok(false)` if the expression is valid and the authorizations are not sufficient.
+    /// * `Err(AccessError::Parse)` if the expression is invalid.
+    pub fn can_access(&self, expression: &str) -> Result<bool, AccessError> {
+        #[cfg(feature = "caching")]
+        if let Some(cache) = &self.expression_cache {
+            let auth_expr = cache.parse(expression);
+            return match auth_expr {
+                Ok(auth_expr) => Ok(self.decide(Some(expression), &auth_expr)),
+                Err(error) => Err(self.reject(expression, error)),
+            };
+        }
+        match Parser::new(Lexer::new(expression)).parse() {
+            Ok(auth_expr) => Ok(self.decide(Some(expression), &auth_expr)),
+            Err(error) => Err(self.reject(expression, error)),
         }
     }
-}
 
-pub struct AccessEvaluator {}
+    /// Checks if the authorizations grant access to the resource protected by an already parsed expression.
+    pub fn can_access_expression(&self, expression: &AuthorizationExpression) -> bool {
+        self.decide(None, expression)
+    }
+
+    fn audit_sink(&self) -> Option<(&dyn audit::AuditSink, Option<&str>)> {
+        match &self.audit {
+            Some((sink, principal)) => Some((sink.as_ref(), Some(principal))),
+            None => audit::audit_sink().map(|sink| (sink, None)),
+        }
+    }
+
+    fn decide(&self, text: Option<&str>, expression: &AuthorizationExpression) -> bool {
+        let granted = expression.evaluate_parsed_from(text, &self.authorizations);
+        if let Some((sink, principal)) = self.audit_sink() {
+            let text = text.map_or_else(|| expression.to_expression_str(), str::to_string);
+            sink.record(&audit::AuditRecord::new(principal, &text, &self.authorizations, Ok((expression, granted))));
+        }
+        granted
+    }
+
+    fn reject(&self, text: &str, error: ParserError) -> AccessError {
+        if let Some((sink, principal)) = self.audit_sink() {
+            sink.record(&audit::AuditRecord::new(principal, text, &self.authorizations, Err(&error)));
+        }
+        AccessError::Parse(error)
+    }
+}
 
 /// Checks if the given set of access tokens authorizes access to the resource which protection is described by the given expression.
 ///
@@ -38,7 +163,7 @@ pub struct AccessEvaluator {}
 /// Returns:
 /// * `Ok(true)` if the expression is valid and the tokens are authorized.
 /// * `Ok(false)` if the expression is valid and the tokens are not authorized.
-/// * `Err(ParserError)` if the expression is invalid.
+/// * `Err(AccessError::Parse)` if the expression is invalid.
 ///
 /// # Examples
 /// ```
@@ -58,18 +183,76 @@ pub struct AccessEvaluator {}
 ///     Err(_) => panic!("Unexpected error"),
 ///    };
 /// ```
-pub fn check_authorization(expression: &str, tokens: &[String]) -> Result<bool, ParserError> {
+pub fn check_authorization(expression: &str, tokens: &[String]) -> Result<bool, AccessError> {
+    Ok(audited_check_authorization(expression, tokens).0?)
+}
+
+/// Same as [`check_authorization`], also returning the record passed to the process-wide audit
+/// sink, if one is installed.
+pub(crate) fn audited_check_authorization(expression: &str, tokens: &[String]) -> (Result<bool, ParserError>, Option<audit::AuditRecord>) {
+    #[cfg(feature = "tracing")]
+    let span = tracing::debug_span!(
+        "check_authorization",
+        expression.fingerprint = %trace::fingerprint(expression),
+        authorization_count = tokens.len(),
+        decision = tracing::field::Empty,
+        error = tracing::field::Empty,
+    );
+    #[cfg(feature = "tracing")]
+    let _entered = span.enter();
+
     let lexer: Lexer<'_> = Lexer::new(expression);
     let mut parser = Parser::new(lexer);
 
-    let auth_expr = parser.parse()?;
+    let auth_expr = parser.parse();
+    #[cfg(feature = "tracing")]
+    if let Err(error) = &auth_expr {
+        span.record("error", tracing::field::display(error));
+    }
     let authorized_labels = tokens.iter().cloned().collect();
-    let result = auth_expr.evaluate(&authorized_labels);
-    Ok(result)
+    let result = auth_expr.as_ref().map(|auth_expr| auth_expr.evaluate_parsed_from(Some(expression), &authorized_labels));
+    let record = audit::audit_sink().map(|sink| {
+        let outcome = auth_expr.as_ref().map(|auth_expr| (auth_expr, result == Ok(true)));
+        let record = audit::AuditRecord::new(None, expression, &authorized_labels, outcome);
+        sink.record(&record);
+        record
+    });
+    let result = result.map_err(Clone::clone);
+    #[cfg(feature = "tracing")]
+    if let Ok(decision) = result {
+        span.record("decision", decision);
+    }
+    (result, record)
+}
+
+/// Checks if every set of access tokens that is authorized by `expression` is also authorized by `other`.
+///
+/// Arguments:
+/// * `expression` - The expression that should imply `other`.
+/// * `other` - The expression to check against.
+///
+/// Returns:
+/// * `Ok(true)` if both expressions are valid and `expression` implies `other`.
+/// * `Ok(false)` if both expressions are valid and some set of access tokens authorizes `expression` but not `other`.
+/// * `Err(AccessError::Parse)` if either expression is invalid.
+/// * `Err(AccessError::Limit)` if the disjunctive normal form of `expression` has more than
+///   [`ANALYSIS_DNF_LIMIT`] terms.
+///
+/// # Examples
+/// ```
+/// use accumulo_access::check_implication;
+///
+/// assert_eq!(check_implication("label1&label2", "label1|label3"), Ok(true));
+/// assert_eq!(check_implication("label1|label3", "label1"), Ok(false));
+/// ```
+pub fn check_implication(expression: &str, other: &str) -> Result<bool, AccessError> {
+    let expression = Parser::new(Lexer::new(expression)).parse()?;
+    let other = Parser::new(Lexer::new(other)).parse()?;
+    Ok(expression.implies(&other)?)
 }
 
 // Prepares a function that can be used to check if the given set of access tokens authorizes access to the resource which protection is described by the given expression.
-pub fn prepare_authorization_csv(tokens: String) -> impl Fn(String) -> Result<bool, ParserError> {
+pub fn prepare_authorization_csv(tokens: String) -> impl Fn(String) -> Result<bool, AccessError> {
     let tokens: Vec<String> = tokens.split(',').map(|s| s.to_string()).collect();
     move |expression| check_authorization(expression.as_str(), &tokens)
 }
@@ -78,32 +261,22 @@ pub fn prepare_authorization_csv(tokens: String) -> impl Fn(String) -> Result<bo
 pub fn check_authorization_csv(
     expression: String,
     tokens: String,
-) -> Result<bool, ParserError> {
+) -> Result<bool, AccessError> {
     prepare_authorization_csv(tokens)(expression)
 }
 
-pub fn expression_to_json_string(expression: &str) -> Result<String, ParserError> {
+pub fn expression_to_json_string(expression: &str) -> Result<String, AccessError> {
     let lexer: Lexer<'_> = Lexer::new(expression);
     let mut parser = Parser::new(lexer);
-
-    let auth_expr = parser.parse();
-    
-    match auth_expr {
-        Ok(auth_expr) => {
-            Ok(auth_expr.to_json_str())
-        }
-        Err(e) => Err(e),
-    }
+    let expr = parser.parse()?;
+    Ok(expr.to_json_str())
 }
 
-pub fn expression_to_json(expression: &str) -> Result<serde_json::Value, JsonError> {
+pub fn expression_to_json(expression: &str) -> Result<serde_json::Value, AccessError> {
     let lexer: Lexer<'_> = Lexer::new(expression);
     let mut parser = Parser::new(lexer);
-    let expr = parser.parse();
-    match expr {
-        Ok(expr) => Ok(expr.to_json()),
-        Err(e) => Err(JsonError::ParsingFailed(format!("{:?}", e))),
-    }
+    let expr = parser.parse()?;
+    Ok(expr.to_json())
 }
 
 #[cfg(test)]
@@ -141,6 +314,46 @@ mod tests {
         assert_eq!(result, expected);
     }
 
+    #[rstest]
+    #[case("A&B|C", ParserError::MixingOperators, 4)]
+    #[case("(A|B)&(C|D&E)", ParserError::MixingOperators, 11)]
+    #[case("A B", ParserError::LexerError(LexerError::UnexpectedCharacter(' ', 2)), 2)]
+    #[case("\"🕺\"&[", ParserError::LexerError(LexerError::UnexpectedCharacter('[', 5)), 5)]
+    fn parser_reports_the_position_of_errors(#[case] expr: &str, #[case] error: ParserError, #[case] position: usize) {
+        let mut parser = Parser::new(Lexer::new(expr));
+        assert_eq!(parser.parse(), Err(error));
+        assert_eq!(parser.position(), position);
+    }
+
+    #[test]
+    fn access_evaluator_test() {
+        let evaluator = AccessEvaluator::of(&Authorizations::of(&["A".to_string(), "B".to_string()]));
+        assert_eq!(evaluator.can_access("A&B"), Ok(true));
+        assert_eq!(evaluator.can_access("A&C"), Ok(false));
+        assert_eq!(evaluator.can_access("A&B|C"), Err(AccessError::Parse(ParserError::MixingOperators)));
+
+        #[cfg(feature = "caching")]
+        {
+            let cache = Arc::new(caching::ExpressionCache::new(10));
+            let evaluator = evaluator.with_expression_cache(cache.clone());
+            assert_eq!(evaluator.can_access("A&B"), Ok(true));
+            assert_eq!(evaluator.can_access("A&B"), Ok(true));
+            assert_eq!(evaluator.can_access("A&B|C"), Err(AccessError::Parse(ParserError::MixingOperators)));
+            assert_eq!(cache.stats().hits, 1);
+            assert_eq!(cache.stats().size, 2);
+        }
+    }
+
+    #[test]
+    fn check_implication_test() {
+        assert_eq!(check_implication("A&B", "A|C"), Ok(true));
+        assert_eq!(check_implication("A|C", "A"), Ok(false));
+        assert_eq!(check_implication("A&B|C", "A"), Err(AccessError::Parse(ParserError::MixingOperators)));
+
+        let product = (0..20).map(|i| format!("(A{i}|B{i})")).collect::<Vec<_>>().join("&");
+        assert_eq!(check_implication(&product, "A0"), Err(AccessError::Limit(NormalFormError::LimitExceeded(ANALYSIS_DNF_LIMIT))));
+    }
+
     #[test]
     fn normalization_test() {
         let expression = "A&B&A&(D|E)&(E|D)"; // -> A&B&(D|E)
@@ -0,0 +1,355 @@
+// Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
+// Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE_MIT or LICENSE_APACHE files.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::analysis::{dualize, minimal_normal_form, Term};
+use crate::authorization_expression::AuthorizationExpression;
+
+/// The number of distinct labels up to which [`AuthorizationExpression::minimize`] searches all factorizations.
+pub const DEFAULT_EXACT_MINIMIZATION_THRESHOLD: usize = 8;
+
+/// The maximum number of clauses the heuristic minimizer produces when converting a DNF to CNF.
+const HEURISTIC_DUALIZATION_LIMIT: usize = 4096;
+
+/// The maximum number of terms of the DNF minimization starts from; expressions with larger DNFs are
+/// minimized scope by scope.
+const MINIMIZATION_DNF_LIMIT: usize = 1024;
+
+struct Minimizer {
+    exact: bool,
+    memo: HashMap<Vec<Term>, AuthorizationExpression>,
+}
+
+impl Minimizer {
+    fn new(exact: bool) -> Self {
+        Minimizer {
+            exact,
+            memo: HashMap::new(),
+        }
+    }
+
+    fn dualization_limit(&self) -> Option<usize> {
+        if self.exact {
+            None
+        } else {
+            Some(HEURISTIC_DUALIZATION_LIMIT)
+        }
+    }
+
+    /// Builds the smallest expression found for the function described by a minimal DNF.
+    fn factor(&mut self, dnf: Vec<Term>) -> AuthorizationExpression {
+        if let Some(expr) = self.memo.get(&dnf) {
+            return expr.clone();
+        }
+        let expr = self.factor_uncached(&dnf);
+        self.memo.insert(dnf, expr.clone());
+        expr
+    }
+
+    fn factor_uncached(&mut self, dnf: &[Term]) -> AuthorizationExpression {
+        match dnf {
+            [] => return AuthorizationExpression::DisjunctionOf(vec![]),
+            [term] => return conjunction(labels(term)),
+            _ => {}
+        }
+
+        // A&B | A&C = A&(B|C)
+        let common = intersection(dnf);
+        if !common.is_empty() {
+            let rest = dnf.iter().map(|term| term.difference(&common).cloned().collect()).collect();
+            let mut nodes = labels(&common);
+            nodes.push(self.factor(rest));
+            return conjunction(nodes);
+        }
+
+        // A&B | C&D, where no label is shared between the two groups.
+        let groups = connected_components(dnf);
+        if groups.len() > 1 {
+            let nodes = groups.into_iter().map(|group| self.factor(group)).collect();
+            return disjunction(nodes);
+        }
+
+        let cnf = dualize(dnf, self.dualization_limit());
+        if let Some(cnf) = &cnf {
+            // (A|B) & (A|C) = A|(B&C)
+            let common = intersection(cnf);
+            if !common.is_empty() {
+                let rest: Vec<Term> = cnf.iter().map(|clause| clause.difference(&common).cloned().collect()).collect();
+                if let Some(rest) = dualize(&rest, self.dualization_limit()) {
+                    let mut nodes = labels(&common);
+                    nodes.push(self.factor(rest));
+                    return disjunction(nodes);
+                }
+            }
+
+            // (A|B) & (C|D), where no label is shared between the two groups.
+            let groups = connected_components(cnf);
+            if groups.len() > 1 {
+                let groups: Option<Vec<Vec<Term>>> = groups.iter()
+                    .map(|group| dualize(group, self.dualization_limit()))
+                    .collect();
+                if let Some(groups) = groups {
+                    let nodes = groups.into_iter().map(|group| self.factor(group)).collect();
+                    return conjunction(nodes);
+                }
+            }
+        }
+
+        let mut best: Option<AuthorizationExpression> = None;
+        for label in self.split_candidates(dnf) {
+            let (with, without) = split(dnf, &label);
+            let candidate = disjunction(vec![
+                conjunction(vec![AuthorizationExpression::AccessToken(label.clone()), self.factor(with)]),
+                self.factor(without),
+            ]);
+            best = Some(smallest(best, candidate));
+        }
+        if let (true, Some(cnf)) = (self.exact, &cnf) {
+            for label in self.split_candidates(cnf) {
+                let (with, without) = split(cnf, &label);
+                let (Some(with), Some(without)) = (dualize(&with, None), dualize(&without, None)) else {
+                    continue;
+                };
+                let candidate = conjunction(vec![
+                    disjunction(vec![AuthorizationExpression::AccessToken(label.clone()), self.factor(with)]),
+                    self.factor(without),
+                ]);
+                best = Some(smallest(best, candidate));
+            }
+        }
+        best.expect("a DNF with several terms has at least one label")
+    }
+
+    /// Exact minimization tries every label; the heuristic only tries the most frequent one.
+    fn split_candidates(&self, sets: &[Term]) -> Vec<String> {
+        let mut frequencies: BTreeMap<&String, usize> = BTreeMap::new();
+        for label in sets.iter().flatten() {
+            *frequencies.entry(label).or_default() += 1;
+        }
+        if self.exact {
+            return frequencies.into_keys().cloned().collect();
+        }
+        frequencies.into_iter()
+            .max_by(|(a, x), (b, y)| x.cmp(y).then_with(|| b.cmp(a)))
+            .map(|(label, _)| vec![label.clone()])
+            .unwrap_or_default()
+    }
+}
+
+fn intersection(sets: &[Term]) -> Term {
+    let mut iter = sets.iter();
+    let mut common = iter.next().cloned().unwrap_or_default();
+    for set in iter {
+        common.retain(|label| set.contains(label));
+    }
+    common
+}
+
+/// Splits the sets into those containing `label` (with the label removed) and the rest.
+fn split(sets: &[Term], label: &String) -> (Vec<Term>, Vec<Term>) {
+    let mut with = Vec::new();
+    let mut without = Vec::new();
+    for set in sets {
+        if set.contains(label) {
+            let mut set = set.clone();
+            set.remove(label);
+            with.push(set);
+        } else {
+            without.push(set.clone());
+        }
+    }
+    (with, without)
+}
+
+/// Groups the sets so that no label occurs in more than one group.
+fn connected_components(sets: &[Term]) -> Vec<Vec<Term>> {
+    let mut groups: Vec<Vec<Term>> = Vec::new();
+    for set in sets {
+        let (overlapping, disjoint): (Vec<_>, Vec<_>) = groups.into_iter()
+            .partition(|group| group.iter().any(|other| !other.is_disjoint(set)));
+        let mut merged: Vec<Term> = overlapping.into_iter().flatten().collect();
+        merged.push(set.clone());
+        groups = disjoint;
+        groups.push(merged);
+    }
+    for group in &mut groups {
+        group.sort();
+    }
+    groups.sort();
+    groups
+}
+
+fn labels(term: &Term) -> Vec<AuthorizationExpression> {
+    term.iter().cloned().map(AuthorizationExpression::AccessToken).collect()
+}
+
+pub(crate) fn conjunction(nodes: Vec<AuthorizationExpression>) -> AuthorizationExpression {
+    let mut flattened = Vec::with_capacity(nodes.len());
+    for node in nodes {
+        match node {
+            AuthorizationExpression::Nil => {}
+            AuthorizationExpression::ConjunctionOf(children) => flattened.extend(children),
+            _ => flattened.push(node),
+        }
+    }
+    match flattened.len() {
+        0 => AuthorizationExpression::Nil,
+        1 => flattened.pop().unwrap(),
+        _ => AuthorizationExpression::ConjunctionOf(sorted(flattened)),
+    }
+}
+
+pub(crate) fn disjunction(nodes: Vec<AuthorizationExpression>) -> AuthorizationExpression {
+    let mut flattened = Vec::with_capacity(nodes.len());
+    for node in nodes {
+        match node {
+            AuthorizationExpression::Nil => return AuthorizationExpression::Nil,
+            AuthorizationExpression::DisjunctionOf(children) => flattened.extend(children),
+            _ => flattened.push(node),
+        }
+    }
+    match flattened.len() {
+        1 => flattened.pop().unwrap(),
+        _ => AuthorizationExpression::DisjunctionOf(sorted(flattened)),
+    }
+}
+
+/// Orders access tokens before nested scopes, so that the output reads like `A&(B|C)`.
+fn sorted(mut nodes: Vec<AuthorizationExpression>) -> Vec<AuthorizationExpression> {
+    nodes.sort_by_cached_key(|node| (!matches!(node, AuthorizationExpression::AccessToken(_)), node.to_expression_str()));
+    nodes
+}
+
+fn leaf_count(expr: &AuthorizationExpression) -> usize {
+    match expr {
+        AuthorizationExpression::ConjunctionOf(nodes) | AuthorizationExpression::DisjunctionOf(nodes) =>
+            nodes.iter().map(leaf_count).sum(),
+        AuthorizationExpression::AccessToken(_) => 1,
+        AuthorizationExpression::Nil => 0,
+    }
+}
+
+fn compare_size(a: &AuthorizationExpression, b: &AuthorizationExpression) -> Ordering {
+    let a_str = a.to_expression_str();
+    let b_str = b.to_expression_str();
+    leaf_count(a).cmp(&leaf_count(b))
+        .then_with(|| a_str.len().cmp(&b_str.len()))
+        .then_with(|| a_str.cmp(&b_str))
+}
+
+fn smallest(current: Option<AuthorizationExpression>, candidate: AuthorizationExpression) -> AuthorizationExpression {
+    match current {
+        Some(current) if compare_size(&current, &candidate) != Ordering::Greater => current,
+        _ => candidate,
+    }
+}
+
+impl AuthorizationExpression {
+    /// Produce a small equivalent expression by removing redundant terms (absorption) and factoring
+    /// out shared labels.
+    ///
+    /// Expressions referencing at most [`DEFAULT_EXACT_MINIMIZATION_THRESHOLD`] distinct labels are
+    /// minimized by searching all factorizations; larger expressions are factored greedily by label frequency.
+    /// Expressions whose DNF would be too large (e.g. `(A1|B1)&(A2|B2)&...`) keep their structure, and
+    /// each of their scopes is minimized on its own. The result can be parsed back from its string
+    /// representation, except for expressions no authorizations satisfy: only trees with an empty
+    /// disjunction are, and no text says so, so they are returned unchanged (and rejected by
+    /// [`AccessExpression::try_from`](crate::AccessExpression)).
+    ///
+    /// # Example
+    /// ```
+    /// use accumulo_access::{Lexer, Parser};
+    /// let expr = Parser::new(Lexer::new("(A&B)|(A&C)|(A&B&D)")).parse().unwrap();
+    /// let minimized = expr.minimize();
+    /// assert_eq!(minimized.to_expression_str(), "A&(B|C)");
+    /// assert_eq!(minimized.is_equivalent(&expr), Ok(true));
+    /// ```
+    pub fn minimize(&self) -> AuthorizationExpression {
+        self.minimize_with_threshold(DEFAULT_EXACT_MINIMIZATION_THRESHOLD)
+    }
+
+    /// Same as [`AuthorizationExpression::minimize`], but with a custom number of distinct labels up to
+    /// which all factorizations are searched.
+    pub fn minimize_with_threshold(&self, exact_threshold: usize) -> AuthorizationExpression {
+        let Some(dnf) = minimal_normal_form(self, true, Some(MINIMIZATION_DNF_LIMIT)) else {
+            let minimize = |nodes: &[AuthorizationExpression]| {
+                nodes.iter().map(|node| node.minimize_with_threshold(exact_threshold)).collect()
+            };
+            return match self {
+                AuthorizationExpression::ConjunctionOf(nodes) => conjunction(minimize(nodes)),
+                AuthorizationExpression::DisjunctionOf(nodes) => disjunction(minimize(nodes)),
+                _ => unreachable!("labels and the empty expression have a DNF of one term"),
+            };
+        };
+        if dnf.is_empty() {
+            return self.clone();
+        }
+        let label_count = dnf.iter().flatten().collect::<std::collections::BTreeSet<_>>().len();
+        Minimizer::new(label_count <= exact_threshold).factor(dnf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Lexer, Parser};
+    use rstest::rstest;
+
+    fn parse(expression: &str) -> AuthorizationExpression {
+        Parser::new(Lexer::new(expression)).parse().unwrap()
+    }
+
+    #[rstest]
+    #[case("(A&B)|(A&C)|(A&B&D)", "A&(B|C)")]
+    #[case("(A|B)&(A|C)", "A|(B&C)")]
+    #[case("(A&C)|(A&D)|(B&C)|(B&D)", "(A|B)&(C|D)")]
+    #[case("A|(A&B)", "A")]
+    #[case("A&A&B", "A&B")]
+    #[case("(A&B)|(C&D)", "(A&B)|(C&D)")]
+    #[case("(A&B&C)|(A&B&D)|(E&F)", "(A&B&(C|D))|(E&F)")]
+    #[case("\"a b\"|(\"a b\"&C)", "\"a b\"")]
+    #[case("", "")]
+    fn test_minimize(#[case] expression: &str, #[case] expected: &str) {
+        let expr = parse(expression);
+        let minimized = expr.minimize();
+        assert_eq!(minimized.to_expression_str(), expected);
+        assert_eq!(minimized.is_equivalent(&expr), Ok(true));
+    }
+
+    #[rstest]
+    #[case("(A&B)|(A&C)|(A&B&D)")]
+    #[case("(A&C)|(A&D)|(B&C)|(B&D)")]
+    #[case("(A&B&C)|(A&B&D)|(A&E)|(F&(G|H))")]
+    #[case("(A&(B|(C&(D|(E&F)))))|(G&H)")]
+    fn minimized_expressions_round_trip(#[case] expression: &str) {
+        let expr = parse(expression);
+        for threshold in [0, DEFAULT_EXACT_MINIMIZATION_THRESHOLD] {
+            let minimized = expr.minimize_with_threshold(threshold);
+            let reparsed = parse(&minimized.to_expression_str());
+            assert_eq!(reparsed.is_equivalent(&expr), Ok(true));
+            assert!(leaf_count(&minimized) <= leaf_count(&expr));
+        }
+    }
+
+    #[test]
+    fn expressions_with_large_dnfs_are_minimized_per_scope() {
+        // (A0|B0)&(A1|B1)&... has 2^40 DNF terms.
+        let expression = (0..40).map(|i| format!("(A{i}|B{i}|(A{i}&C{i}))")).collect::<Vec<_>>().join("&");
+        let expected = (0..40).map(|i| format!("(A{i}|B{i})")).collect::<Vec<_>>().join("&");
+        assert_eq!(parse(&expression).minimize(), parse(&expected));
+    }
+
+    // An empty disjunction grants access to nobody, which no text can say ("" grants everyone).
+    #[test]
+    fn unsatisfiable_expressions_are_kept() {
+        let nobody = AuthorizationExpression::DisjunctionOf(vec![]);
+        let expr = AuthorizationExpression::ConjunctionOf(vec![parse("A|(A&B)"), nobody.clone()]);
+        for expr in [nobody, expr] {
+            let minimized = expr.minimize();
+            assert_eq!(minimized, expr);
+            assert!(crate::AccessExpression::try_from(minimized).is_err());
+        }
+    }
+}
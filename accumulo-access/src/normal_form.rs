@@ -0,0 +1,197 @@
+// Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
+// Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE_MIT or LICENSE_APACHE files.
+
+use std::collections::BTreeSet;
+use thiserror::Error;
+
+use crate::analysis::minimal_normal_form;
+use crate::authorization_expression::AuthorizationExpression;
+use crate::minimize::{conjunction, disjunction};
+
+/// The default maximum number of terms (DNF) or clauses (CNF) produced by a normal form conversion.
+pub const DEFAULT_NORMAL_FORM_LIMIT: usize = 10_000;
+
+/// `NormalFormError` is returned when a normal form conversion blows up.
+#[derive(Error, Debug, PartialEq, Clone)]
+pub enum NormalFormError {
+    /// The conversion produced more terms or clauses than the configured limit.
+    LimitExceeded(usize),
+}
+
+impl std::fmt::Display for NormalFormError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            NormalFormError::LimitExceeded(limit) => write!(f, "Normal form exceeds the limit of {} clauses", limit),
+        }
+    }
+}
+
+/// The kind of a [`NormalForm`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum NormalFormKind {
+    /// Disjunctive normal form: any of the clauses must be satisfied, each clause requires all of its labels.
+    Disjunctive,
+    /// Conjunctive normal form: all of the clauses must be satisfied, each clause requires any of its labels.
+    Conjunctive,
+}
+
+/// A minimal disjunctive or conjunctive normal form of an expression.
+///
+/// The clauses are minimal (no clause is a superset of another) and sorted, so equivalent
+/// expressions have identical normal forms.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct NormalForm {
+    kind: NormalFormKind,
+    clauses: Vec<BTreeSet<String>>,
+}
+
+impl NormalForm {
+    pub fn kind(&self) -> NormalFormKind {
+        self.kind
+    }
+
+    /// The clauses (DNF terms, or CNF "any-of" groups) of the normal form.
+    pub fn clauses(&self) -> &[BTreeSet<String>] {
+        &self.clauses
+    }
+
+    pub fn into_clauses(self) -> Vec<BTreeSet<String>> {
+        self.clauses
+    }
+
+    /// Create the expression tree of the normal form.
+    ///
+    /// # Example
+    /// ```
+    /// use accumulo_access::{Lexer, Parser};
+    /// let expr = Parser::new(Lexer::new("A&(B|C)")).parse().unwrap();
+    /// assert_eq!(expr.to_dnf().unwrap().to_expression().to_expression_str(), "(A&B)|(A&C)");
+    /// assert_eq!(expr.to_cnf().unwrap().to_expression().to_expression_str(), "A&(B|C)");
+    /// ```
+    pub fn to_expression(&self) -> AuthorizationExpression {
+        let labels = |clause: &BTreeSet<String>| clause.iter().cloned().map(AuthorizationExpression::AccessToken).collect();
+        match self.kind {
+            NormalFormKind::Disjunctive => disjunction(self.clauses.iter().map(|clause| conjunction(labels(clause))).collect()),
+            NormalFormKind::Conjunctive => conjunction(self.clauses.iter().map(|clause| disjunction(labels(clause))).collect()),
+        }
+    }
+}
+
+impl AuthorizationExpression {
+    /// Convert the expression to its minimal disjunctive normal form, with at most
+    /// [`DEFAULT_NORMAL_FORM_LIMIT`] terms.
+    ///
+    /// # Example
+    /// ```
+    /// use std::collections::BTreeSet;
+    /// use accumulo_access::{Lexer, Parser};
+    /// let expr = Parser::new(Lexer::new("A&(B|C)")).parse().unwrap();
+    /// let dnf = expr.to_dnf().unwrap();
+    /// assert_eq!(dnf.clauses(), [
+    ///   BTreeSet::from(["A".to_string(), "B".to_string()]),
+    ///   BTreeSet::from(["A".to_string(), "C".to_string()]),
+    /// ]);
+    /// ```
+    pub fn to_dnf(&self) -> Result<NormalForm, NormalFormError> {
+        self.to_dnf_with_limit(DEFAULT_NORMAL_FORM_LIMIT)
+    }
+
+    /// Convert the expression to its minimal disjunctive normal form, failing if more than `limit` terms are produced.
+    pub fn to_dnf_with_limit(&self, limit: usize) -> Result<NormalForm, NormalFormError> {
+        self.to_normal_form(NormalFormKind::Disjunctive, limit)
+    }
+
+    /// Convert the expression to its minimal conjunctive normal form, with at most
+    /// [`DEFAULT_NORMAL_FORM_LIMIT`] clauses.
+    ///
+    /// # Example
+    /// ```
+    /// use std::collections::BTreeSet;
+    /// use accumulo_access::{Lexer, Parser};
+    /// let expr = Parser::new(Lexer::new("(A&B)|(A&C)")).parse().unwrap();
+    /// let cnf = expr.to_cnf().unwrap();
+    /// assert_eq!(cnf.clauses(), [
+    ///   BTreeSet::from(["A".to_string()]),
+    ///   BTreeSet::from(["B".to_string(), "C".to_string()]),
+    /// ]);
+    /// ```
+    pub fn to_cnf(&self) -> Result<NormalForm, NormalFormError> {
+        self.to_cnf_with_limit(DEFAULT_NORMAL_FORM_LIMIT)
+    }
+
+    /// Convert the expression to its minimal conjunctive normal form, failing if more than `limit` clauses are produced.
+    pub fn to_cnf_with_limit(&self, limit: usize) -> Result<NormalForm, NormalFormError> {
+        self.to_normal_form(NormalFormKind::Conjunctive, limit)
+    }
+
+    fn to_normal_form(&self, kind: NormalFormKind, limit: usize) -> Result<NormalForm, NormalFormError> {
+        let clauses = minimal_normal_form(self, kind == NormalFormKind::Disjunctive, Some(limit))
+            .ok_or(NormalFormError::LimitExceeded(limit))?;
+        Ok(NormalForm { kind, clauses })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Lexer, Parser};
+    use rstest::rstest;
+
+    fn parse(expression: &str) -> AuthorizationExpression {
+        Parser::new(Lexer::new(expression)).parse().unwrap()
+    }
+
+    #[rstest]
+    #[case("A&(B|C)", "(A&B)|(A&C)", "A&(B|C)")]
+    #[case("(A|B)&(C|D)", "(A&C)|(A&D)|(B&C)|(B&D)", "(A|B)&(C|D)")]
+    #[case("(A&B)|(A&C)", "(A&B)|(A&C)", "A&(B|C)")]
+    #[case("A|(A&B)", "A", "A")]
+    #[case("", "", "")]
+    fn test_normal_forms(#[case] expression: &str, #[case] dnf: &str, #[case] cnf: &str) {
+        let expr = parse(expression);
+
+        let actual_dnf = expr.to_dnf().unwrap();
+        assert_eq!(actual_dnf.kind(), NormalFormKind::Disjunctive);
+        assert_eq!(actual_dnf.to_expression().to_expression_str(), dnf);
+        assert_eq!(actual_dnf.to_expression().is_equivalent(&expr), Ok(true));
+
+        let actual_cnf = expr.to_cnf().unwrap();
+        assert_eq!(actual_cnf.kind(), NormalFormKind::Conjunctive);
+        assert_eq!(actual_cnf.to_expression().to_expression_str(), cnf);
+        assert_eq!(actual_cnf.to_expression().is_equivalent(&expr), Ok(true));
+    }
+
+    #[test]
+    fn nil_has_one_empty_term_and_no_clauses() {
+        let expr = AuthorizationExpression::Nil;
+        assert_eq!(expr.to_dnf().unwrap().into_clauses(), vec![BTreeSet::new()]);
+        assert!(expr.to_cnf().unwrap().into_clauses().is_empty());
+    }
+
+    #[test]
+    fn normal_form_expressions_round_trip() {
+        let expr = parse("(A|B)&(C|\"d e\")&F");
+        for form in [expr.to_dnf().unwrap(), expr.to_cnf().unwrap()] {
+            let reparsed = parse(&form.to_expression().to_expression_str());
+            assert_eq!(reparsed.is_equivalent(&expr), Ok(true));
+        }
+    }
+
+    #[test]
+    fn blow_up_limit_is_enforced() {
+        // (A1|B1)&(A2|B2)&... has 2^n DNF terms but only n CNF clauses.
+        let expression = (0..10).map(|i| format!("(A{i}|B{i})")).collect::<Vec<_>>().join("&");
+        let expr = parse(&expression);
+        assert_eq!(expr.to_dnf_with_limit(500), Err(NormalFormError::LimitExceeded(500)));
+        assert_eq!(expr.to_dnf_with_limit(1024).unwrap().clauses().len(), 1024);
+        assert_eq!(expr.to_cnf_with_limit(10).unwrap().clauses().len(), 10);
+    }
+
+    #[test]
+    fn blow_up_is_detected_before_multiplying() {
+        // Both operands are within the limit, but their product of 250000 terms is not.
+        let operand = |prefix: &str| (0..500).map(|i| format!("{prefix}{i}")).collect::<Vec<_>>().join("|");
+        let expr = parse(&format!("({})&({})", operand("A"), operand("B")));
+        assert_eq!(expr.to_dnf_with_limit(1000), Err(NormalFormError::LimitExceeded(1000)));
+    }
+}
@@ -17,7 +17,7 @@ pub enum ParserError {
     /// The parser encountered a mix of operators ('&' and '|').
     MixingOperators,
     /// The parser encountered a lexer error.
-    LexerError(crate::lexer::LexerError),
+    LexerError(#[source] crate::lexer::LexerError),
 }
 
 impl std::fmt::Display for ParserError {
@@ -32,14 +32,48 @@ impl std::fmt::Display for ParserError {
     }
 }
 
+/// What a scope is built into: the `AuthorizationExpression` tree when parsing, or nothing at all
+/// when only validating, so both follow the same rules.
+trait Node: Sized {
+    fn nil() -> Self;
+    fn access_token(label: String) -> Self;
+    fn scope(operator: Operator, nodes: Vec<Self>) -> Self;
+}
+
+impl Node for AuthorizationExpression {
+    fn nil() -> Self {
+        AuthorizationExpression::Nil
+    }
+
+    fn access_token(label: String) -> Self {
+        AuthorizationExpression::AccessToken(label)
+    }
+
+    fn scope(operator: Operator, nodes: Vec<Self>) -> Self {
+        match operator {
+            Operator::Conjunction => AuthorizationExpression::ConjunctionOf(nodes),
+            Operator::Disjunction => AuthorizationExpression::DisjunctionOf(nodes),
+        }
+    }
+}
+
+/// Validation keeps no nodes; a `Vec<()>` never allocates.
+impl Node for () {
+    fn nil() -> Self {}
+
+    fn access_token(_label: String) -> Self {}
+
+    fn scope(_operator: Operator, _nodes: Vec<Self>) -> Self {}
+}
+
 #[derive(Debug)]
-struct Scope {
-    nodes: Vec<AuthorizationExpression>,
-    access_tokens: Vec<String>,
+struct Scope<N> {
+    nodes: Vec<N>,
+    access_tokens: Vec<N>,
     operator: Option<Operator>,
 }
 
-impl Scope {
+impl<N: Node> Scope<N> {
     fn new() -> Self {
         Scope {
             nodes: Vec::new(),
@@ -48,12 +82,12 @@ impl Scope {
         }
     }
 
-    fn append_node(&mut self, token: AuthorizationExpression) {
+    fn append_node(&mut self, token: N) {
         self.nodes.push(token);
     }
 
     fn append_access_token(&mut self, label: String) {
-        self.access_tokens.push(label);
+        self.access_tokens.push(N::access_token(label));
     }
 
     fn disjunction(&mut self) -> Result<(), ParserError> {
@@ -81,15 +115,13 @@ impl Scope {
         Ok(())
     }
 
-    fn build(&mut self) -> Result<AuthorizationExpression, ParserError> {
+    fn build(&mut self) -> Result<N, ParserError> {
        if self.access_tokens.is_empty() && self.nodes.is_empty() {
-           return Ok(AuthorizationExpression::Nil)
+           return Ok(N::nil())
        }
        
         if self.access_tokens.len() == 1 && self.nodes.is_empty() {
-            return Ok(AuthorizationExpression::AccessToken(
-                self.access_tokens.pop().unwrap(),
-            ));
+            return Ok(self.access_tokens.pop().unwrap());
         }
         // if it is a scope wrapping a single node, return the node
         if self.nodes.len() == 1 && self.access_tokens.is_empty() {
@@ -102,16 +134,13 @@ impl Scope {
         let mut nodes = Vec::with_capacity(self.access_tokens.len() + self.nodes.len());
 
         while let Some(label) = self.access_tokens.pop() {
-            nodes.push(AuthorizationExpression::AccessToken(label));
+            nodes.push(label);
         }
 
         while let Some(token) = self.nodes.pop() {
             nodes.push(token);
         }
-        match operator {
-            Operator::Conjunction => Ok(AuthorizationExpression::ConjunctionOf(nodes)),
-            Operator::Disjunction => Ok(AuthorizationExpression::DisjunctionOf(nodes))
-        }
+        Ok(N::scope(operator, nodes))
     }
 }
 
@@ -149,6 +178,59 @@ impl<'a> Parser<'a> {
     ///  assert_eq!(ast.evaluate(&authorized_tokens), true);
     /// ```
     pub fn parse(&mut self) -> Result<AuthorizationExpression, ParserError> {
+        #[cfg(feature = "tracing")]
+        let span = tracing::trace_span!(
+            "parse",
+            expression.fingerprint = %crate::trace::fingerprint(self.lexer.input()),
+            expression.length = self.lexer.input().len(),
+            expression = tracing::field::Empty,
+            label_count = tracing::field::Empty,
+            error = tracing::field::Empty,
+        );
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+        #[cfg(feature = "metrics")]
+        let start = std::time::Instant::now();
+        let result = self.parse_scope();
+        #[cfg(feature = "metrics")]
+        crate::telemetry::record_parse(start.elapsed(), result.as_ref().err());
+        #[cfg(feature = "tracing")]
+        if !span.is_disabled() {
+            match &result {
+                Ok(expr) => {
+                    span.record("expression", crate::trace::expression(expr));
+                    span.record("label_count", crate::trace::label_count(expr));
+                }
+                Err(error) => {
+                    span.record("error", tracing::field::display(error));
+                }
+            }
+        }
+        result
+    }
+
+    /// The number of characters read so far. After an error, this is the position (counting from 1,
+    /// like [`LexerError`](crate::LexerError) positions) of the character the error was detected at,
+    /// e.g. the operator that mixes `&` and `|`, or the last character if it was detected at the end.
+    ///
+    /// # Example
+    /// ```
+    /// use accumulo_access::{Lexer, Parser, ParserError};
+    ///
+    /// let mut parser = Parser::new(Lexer::new("(A&B|C)"));
+    /// assert_eq!(parser.parse(), Err(ParserError::MixingOperators));
+    /// assert_eq!(parser.position(), 5);
+    /// ```
+    pub fn position(&self) -> usize {
+        self.lexer.position()
+    }
+
+    /// Checks the input like [`Parser::parse`] does, without building the tree.
+    pub(crate) fn validate(&mut self) -> Result<(), ParserError> {
+        self.parse_scope()
+    }
+
+    fn parse_scope<N: Node + Clone>(&mut self) -> Result<N, ParserError> {
         let mut scope = Scope::new();
         while let Some(result) = self.lexer.next() {
             match result {
@@ -156,7 +238,7 @@ impl<'a> Parser<'a> {
                     match token {
                         Token::AccessToken(value) => scope.append_access_token(value),
                         Token::OpenParen => {
-                            let node = self.parse()?;
+                            let node: N = self.parse_scope()?;
                             scope.append_node(node.clone()); // The clone here is apparently important.
                         }
                         Token::And => scope.conjunction()?,
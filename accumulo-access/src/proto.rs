@@ -0,0 +1,222 @@
+// Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
+// Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE_MIT or LICENSE_APACHE files.
+
+//! Protocol Buffers messages for expressions, authorizations and decisions.
+//!
+//! The messages are generated by [`prost`](https://docs.rs/prost) from `proto/accumulo_access.proto`
+//! (package `accumulo_access.v1`), which other services can compile to get the same message shapes.
+//! Code generation uses a vendored `protoc`, so building with the `protobuf` feature needs no
+//! system-wide installation.
+//!
+//! # Example
+//! ```
+//! use accumulo_access::proto::{self, DecisionRequest};
+//! use accumulo_access::{AuthorizationExpression, Authorizations, Lexer, Parser};
+//!
+//! let expr = Parser::new(Lexer::new("A&(B|C)")).parse().unwrap();
+//! let request = DecisionRequest {
+//!     visibility: Some(proto::decision_request::Visibility::Tree(proto::Expression::from(&expr))),
+//!     authorizations: Some(proto::Authorizations::from(&Authorizations::of(&["A".to_string(), "C".to_string()]))),
+//!     principal: "alice".to_string(),
+//! };
+//! assert!(request.decide().granted);
+//! ```
+
+use std::collections::HashSet;
+
+use crate::json::validate_tree;
+use crate::{audit, AccessError, AuthorizationExpression, JsonError, Lexer, Parser};
+
+include!(concat!(env!("OUT_DIR"), "/accumulo_access.v1.rs"));
+
+use decision_request::Visibility;
+use expression::Node;
+
+impl From<&AuthorizationExpression> for Expression {
+    fn from(expr: &AuthorizationExpression) -> Self {
+        let operands = |nodes: &[AuthorizationExpression]| Operands {
+            operands: nodes.iter().map(Expression::from).collect(),
+        };
+        let node = match expr {
+            AuthorizationExpression::Nil => None,
+            AuthorizationExpression::AccessToken(token) => Some(Node::Label(token.clone())),
+            AuthorizationExpression::ConjunctionOf(nodes) => Some(Node::And(operands(nodes))),
+            AuthorizationExpression::DisjunctionOf(nodes) => Some(Node::Or(operands(nodes))),
+        };
+        Expression { node }
+    }
+}
+
+impl From<AuthorizationExpression> for Expression {
+    fn from(expr: AuthorizationExpression) -> Self {
+        Expression::from(&expr)
+    }
+}
+
+/// Reads the shape of a node; the rules of the tree itself are checked by `validate_tree`.
+fn read_node(node: Option<Node>) -> AuthorizationExpression {
+    let operands = |operands: Operands| operands.operands.into_iter().map(|operand| read_node(operand.node)).collect();
+    match node {
+        None => AuthorizationExpression::Nil,
+        Some(Node::Label(label)) => AuthorizationExpression::AccessToken(label),
+        Some(Node::And(nodes)) => AuthorizationExpression::ConjunctionOf(operands(nodes)),
+        Some(Node::Or(nodes)) => AuthorizationExpression::DisjunctionOf(operands(nodes)),
+    }
+}
+
+/// Reads and validates an expression tree, with the same rules as [`AuthorizationExpression::from_json`].
+/// Errors point at the offending node by field names and operand indices (e.g. `/and/1/or/0`).
+impl TryFrom<Expression> for AuthorizationExpression {
+    type Error = JsonError;
+
+    fn try_from(expression: Expression) -> Result<Self, Self::Error> {
+        let expr = read_node(expression.node);
+        validate_tree(&expr)?;
+        Ok(expr)
+    }
+}
+
+impl From<&crate::Authorizations> for Authorizations {
+    fn from(authorizations: &crate::Authorizations) -> Self {
+        let mut labels: Vec<String> = authorizations.iter().cloned().collect();
+        labels.sort();
+        Authorizations { labels }
+    }
+}
+
+impl From<Authorizations> for crate::Authorizations {
+    fn from(authorizations: Authorizations) -> Self {
+        crate::Authorizations::of(&authorizations.labels)
+    }
+}
+
+impl DecisionResponse {
+    fn invalid(error: impl ToString) -> Self {
+        DecisionResponse {
+            granted: false,
+            error: error.to_string(),
+        }
+    }
+}
+
+impl DecisionRequest {
+    /// Evaluates the visibility against the authorizations.
+    ///
+    /// The decision is recorded in the process-wide audit sink, if one is installed, on behalf of the
+    /// principal (if set).
+    pub fn decide(&self) -> DecisionResponse {
+        let authorizations: HashSet<String> = self.authorizations.iter().flat_map(|a| a.labels.iter().cloned()).collect();
+        let principal = Some(self.principal.as_str()).filter(|principal| !principal.is_empty());
+
+        let expression = match &self.visibility {
+            Some(Visibility::Expression(text)) => match Parser::new(Lexer::new(text)).parse() {
+                Ok(expression) => Ok(expression),
+                Err(error) => {
+                    if let Some(sink) = audit::audit_sink() {
+                        sink.record(&audit::AuditRecord::new(principal, text, &authorizations, Err(&error)));
+                    }
+                    return DecisionResponse::invalid(AccessError::from(error));
+                }
+            },
+            Some(Visibility::Tree(tree)) => AuthorizationExpression::try_from(tree.clone()).map_err(AccessError::from),
+            Some(Visibility::Encoded(bytes)) => AuthorizationExpression::decode(bytes).map_err(AccessError::from),
+            None => Err(AccessError::Validation("the request has no visibility".to_string())),
+        };
+        let expression = match expression {
+            Ok(expression) => expression,
+            Err(error) => return DecisionResponse::invalid(error),
+        };
+
+        let text = match &self.visibility {
+            Some(Visibility::Expression(text)) => Some(text.as_str()),
+            _ => None,
+        };
+        let granted = expression.evaluate_parsed_from(text, &authorizations);
+        if let Some(sink) = audit::audit_sink() {
+            let text = text.map_or_else(|| expression.to_expression_str(), str::to_string);
+            sink.record(&audit::AuditRecord::new(principal, &text, &authorizations, Ok((&expression, granted))));
+        }
+        DecisionResponse {
+            granted,
+            error: String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InvalidTreeReason;
+    use prost::Message;
+    use rstest::rstest;
+
+    fn parse(expression: &str) -> AuthorizationExpression {
+        Parser::new(Lexer::new(expression)).parse().unwrap()
+    }
+
+    fn label(label: &str) -> Expression {
+        Expression { node: Some(Node::Label(label.to_string())) }
+    }
+
+    fn and(operands: Vec<Expression>) -> Expression {
+        Expression { node: Some(Node::And(Operands { operands })) }
+    }
+
+    fn request(visibility: Visibility, labels: &[&str]) -> DecisionRequest {
+        DecisionRequest {
+            visibility: Some(visibility),
+            authorizations: Some(Authorizations {
+                labels: labels.iter().map(|label| label.to_string()).collect(),
+            }),
+            principal: String::new(),
+        }
+    }
+
+    #[rstest]
+    #[case("")]
+    #[case("A")]
+    #[case("A&B")]
+    #[case("A|(B&\"label 🕺\")")]
+    fn expressions_round_trip_through_the_wire_format(#[case] expression: &str) {
+        let expr = parse(expression);
+        let bytes = Expression::from(&expr).encode_to_vec();
+        let decoded = Expression::decode(bytes.as_slice()).unwrap();
+        assert_eq!(AuthorizationExpression::try_from(decoded), Ok(expr));
+    }
+
+    #[rstest]
+    #[case(and(vec![]), "/and", InvalidTreeReason::NoOperands)]
+    #[case(and(vec![label("A"), Expression { node: None }]), "/and/1", InvalidTreeReason::NestedNull)]
+    #[case(and(vec![label("A"), and(vec![label("")])]), "/and/1/and/0", InvalidTreeReason::EmptyLabel)]
+    #[case(and(vec![label("a\nb")]), "/and/0", InvalidTreeReason::InvalidCharacter('\n'))]
+    fn invalid_trees_are_rejected(#[case] tree: Expression, #[case] pointer: &str, #[case] reason: InvalidTreeReason) {
+        let error = AuthorizationExpression::try_from(tree).unwrap_err();
+        assert_eq!(error, JsonError::InvalidTree { pointer: pointer.to_string(), reason });
+    }
+
+    #[test]
+    fn authorizations_are_sorted() {
+        let authorizations = crate::Authorizations::of(&["B".to_string(), "A".to_string()]);
+        let message = Authorizations::from(&authorizations);
+        assert_eq!(message.labels, ["A", "B"]);
+        assert_eq!(crate::Authorizations::from(message), authorizations);
+    }
+
+    #[rstest]
+    #[case(Visibility::Expression("A&(B|C)".to_string()), true)]
+    #[case(Visibility::Tree(Expression::from(&parse("A&D"))), false)]
+    #[case(Visibility::Encoded(parse("A|D").encode().unwrap()), true)]
+    fn requests_are_decided(#[case] visibility: Visibility, #[case] granted: bool) {
+        let response = request(visibility, &["A", "C"]).decide();
+        assert_eq!(response, DecisionResponse { granted, error: String::new() });
+    }
+
+    #[rstest]
+    #[case(Visibility::Expression("A&B|C".to_string()), "Invalid expression: Mixing operators")]
+    #[case(Visibility::Encoded(vec![9]), "Unsupported encoding version 9")]
+    fn invalid_visibilities_are_reported(#[case] visibility: Visibility, #[case] error: &str) {
+        let response = request(visibility, &["A"]).decide();
+        assert!(!response.granted);
+        assert_eq!(response.error, error);
+    }
+}
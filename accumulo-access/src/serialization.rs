@@ -0,0 +1,385 @@
+// Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
+// Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE_MIT or LICENSE_APACHE files.
+
+//! Serde support for [`AuthorizationExpression`] and [`Authorizations`].
+//!
+//! An `AuthorizationExpression` serializes as its compact expression string by default (e.g.
+//! `"A&(B|C)"`), which is parsed and validated again on deserialization. The tree representation
+//! used by [`AuthorizationExpression::to_json`] (`{"and": ["A", {"or": ["B", "C"]}]}`) is selected per
+//! field with `#[serde(with = "accumulo_access::serialization::tree")]`.
+//!
+//! Both representations work with any serde format. In formats that are not human-readable (e.g.
+//! bincode or CBOR), the tree is encoded as nested enum variants (`nil`, `and`, `or`, `label`)
+//! instead, as these formats can't tell a label from an operator object without a tag.
+//!
+//! An [`AccessExpression`] serializes as its original text, and is validated on deserialization.
+//!
+//! `Authorizations` serialize as a sequence of labels, sorted so the output is deterministic.
+//!
+//! # Example
+//! ```
+//! use accumulo_access::AuthorizationExpression;
+//! use serde::{Deserialize, Serialize};
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct Cell {
+//!     visibility: AuthorizationExpression,
+//!     #[serde(with = "accumulo_access::serialization::tree")]
+//!     visibility_tree: AuthorizationExpression,
+//! }
+//!
+//! let cell: Cell = serde_json::from_str(r#"{"visibility": "A&B", "visibility_tree": {"or": ["A", "B"]}}"#).unwrap();
+//! assert_eq!(cell.visibility, AuthorizationExpression::from_json_str(r#"{"and": ["A", "B"]}"#).unwrap());
+//! assert_eq!(serde_json::to_value(&cell).unwrap()["visibility_tree"], cell.visibility_tree.to_json());
+//! ```
+
+use std::fmt::{self, Formatter};
+
+use serde::de::{self, DeserializeSeed, EnumAccess, MapAccess, SeqAccess, VariantAccess, Visitor};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::error::InvalidTreeReason;
+use crate::json::validate_tree;
+use crate::{AccessExpression, AuthorizationExpression, Authorizations, Lexer, Parser};
+
+impl Serialize for AuthorizationExpression {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        expression::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for AuthorizationExpression {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        expression::deserialize(deserializer)
+    }
+}
+
+impl Serialize for AccessExpression {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for AccessExpression {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let expression = String::deserialize(deserializer)?;
+        AccessExpression::of(expression)
+            .map_err(|err| de::Error::custom(format_args!("invalid access expression: {}", err)))
+    }
+}
+
+impl Serialize for Authorizations {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut labels: Vec<&String> = self.iter().collect();
+        labels.sort();
+        serializer.collect_seq(labels)
+    }
+}
+
+impl<'de> Deserialize<'de> for Authorizations {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let labels = Vec::<String>::deserialize(deserializer)?;
+        Ok(Authorizations::of(&labels))
+    }
+}
+
+/// The compact expression string representation (the default), for use with `#[serde(with = "...")]`.
+pub mod expression {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(expr: &AuthorizationExpression, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&expr.to_expression_str())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<AuthorizationExpression, D::Error> {
+        deserializer.deserialize_str(ExpressionVisitor)
+    }
+
+    struct ExpressionVisitor;
+
+    impl Visitor<'_> for ExpressionVisitor {
+        type Value = AuthorizationExpression;
+
+        fn expecting(&self, formatter: &mut Formatter) -> fmt::Result {
+            formatter.write_str("an access expression string")
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+            Parser::new(Lexer::new(value))
+                .parse()
+                .map_err(|err| E::custom(format_args!("invalid access expression {:?}: {}", value, err)))
+        }
+    }
+}
+
+/// The tree representation of [`AuthorizationExpression::to_json`], for use with `#[serde(with = "...")]`.
+pub mod tree {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(expr: &AuthorizationExpression, serializer: S) -> Result<S::Ok, S::Error> {
+        Tree(expr).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<AuthorizationExpression, D::Error> {
+        let expr = NodeSeed.deserialize(deserializer)?;
+        validate_tree(&expr).map_err(de::Error::custom)?;
+        Ok(expr)
+    }
+}
+
+/// The enum and variant names of a tree node in formats that are not human-readable.
+const NODE: &str = "Node";
+const VARIANTS: &[&str] = &["nil", "and", "or", "label"];
+
+struct Tree<'a>(&'a AuthorizationExpression);
+
+impl Serialize for Tree<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let human_readable = serializer.is_human_readable();
+        let (index, key, nodes) = match self.0 {
+            AuthorizationExpression::Nil if human_readable => return serializer.serialize_unit(),
+            AuthorizationExpression::Nil => return serializer.serialize_unit_variant(NODE, 0, VARIANTS[0]),
+            AuthorizationExpression::AccessToken(label) if human_readable => return serializer.serialize_str(label),
+            AuthorizationExpression::AccessToken(label) => {
+                return serializer.serialize_newtype_variant(NODE, 3, VARIANTS[3], label)
+            }
+            AuthorizationExpression::ConjunctionOf(nodes) => (1, VARIANTS[1], nodes),
+            AuthorizationExpression::DisjunctionOf(nodes) => (2, VARIANTS[2], nodes),
+        };
+        if human_readable {
+            let mut map = serializer.serialize_map(Some(1))?;
+            map.serialize_entry(key, &Operands(nodes))?;
+            map.end()
+        } else {
+            serializer.serialize_newtype_variant(NODE, index, key, &Operands(nodes))
+        }
+    }
+}
+
+struct Operands<'a>(&'a [AuthorizationExpression]);
+
+impl Serialize for Operands<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter().map(Tree))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(variant_identifier, rename_all = "lowercase")]
+enum Tag {
+    Nil,
+    And,
+    Or,
+    Label,
+}
+
+/// Reads the shape of a tree node; the rules of the tree itself are checked by `validate_tree`, like
+/// those of the JSON trees read by [`AuthorizationExpression::from_json`].
+#[derive(Clone, Copy)]
+struct NodeSeed;
+
+impl NodeSeed {
+    fn operator(tag: Tag, operands: Vec<AuthorizationExpression>) -> AuthorizationExpression {
+        match tag {
+            Tag::And => AuthorizationExpression::ConjunctionOf(operands),
+            Tag::Or => AuthorizationExpression::DisjunctionOf(operands),
+            Tag::Nil | Tag::Label => unreachable!("not an operator"),
+        }
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for NodeSeed {
+    type Value = AuthorizationExpression;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        match deserializer.is_human_readable() {
+            true => deserializer.deserialize_any(self),
+            false => deserializer.deserialize_enum(NODE, VARIANTS, self),
+        }
+    }
+}
+
+impl<'de> Visitor<'de> for NodeSeed {
+    type Value = AuthorizationExpression;
+
+    fn expecting(&self, formatter: &mut Formatter) -> fmt::Result {
+        formatter.write_str("a label, an {\"and\": [...]} or {\"or\": [...]} object, or null")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        Ok(AuthorizationExpression::AccessToken(value.to_string()))
+    }
+
+    fn visit_string<E: de::Error>(self, value: String) -> Result<Self::Value, E> {
+        Ok(AuthorizationExpression::AccessToken(value))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(AuthorizationExpression::Nil)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(AuthorizationExpression::Nil)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        self.deserialize(deserializer)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut node = None;
+        while let Some(key) = map.next_key::<String>()? {
+            let tag = match key.as_str() {
+                "and" => Tag::And,
+                "or" => Tag::Or,
+                _ => return Err(de::Error::custom(InvalidTreeReason::UnknownKey(key))),
+            };
+            if node.is_some() {
+                return Err(de::Error::custom(InvalidTreeReason::AmbiguousOperator));
+            }
+            node = Some(Self::operator(tag, map.next_value_seed(OperandsSeed)?));
+        }
+        node.ok_or_else(|| de::Error::custom(InvalidTreeReason::MissingOperator))
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Self::Value, A::Error> {
+        match data.variant()? {
+            (Tag::Nil, variant) => {
+                variant.unit_variant()?;
+                Ok(AuthorizationExpression::Nil)
+            }
+            (Tag::Label, variant) => Ok(AuthorizationExpression::AccessToken(variant.newtype_variant()?)),
+            (tag, variant) => Ok(Self::operator(tag, variant.newtype_variant_seed(OperandsSeed)?)),
+        }
+    }
+}
+
+/// Reads the operand list of an operator.
+struct OperandsSeed;
+
+impl<'de> DeserializeSeed<'de> for OperandsSeed {
+    type Value = Vec<AuthorizationExpression>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for OperandsSeed {
+    type Value = Vec<AuthorizationExpression>;
+
+    fn expecting(&self, formatter: &mut Formatter) -> fmt::Result {
+        formatter.write_str("an array of operands")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut operands = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(64));
+        while let Some(operand) = seq.next_element_seed(NodeSeed)? {
+            operands.push(operand);
+        }
+        Ok(operands)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use serde::de::DeserializeOwned;
+    use std::fmt::Debug;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Cell {
+        visibility: AuthorizationExpression,
+        #[serde(with = "tree")]
+        tree: AuthorizationExpression,
+        authorizations: Authorizations,
+    }
+
+    fn parse(expression: &str) -> AuthorizationExpression {
+        Parser::new(Lexer::new(expression)).parse().unwrap()
+    }
+
+    fn cell(expression: &str) -> Cell {
+        Cell {
+            visibility: parse(expression),
+            tree: parse(expression),
+            authorizations: Authorizations::of(&["B".to_string(), "A".to_string()]),
+        }
+    }
+
+    fn round_trip<T: Serialize + DeserializeOwned + PartialEq + Debug>(value: &T) {
+        let json: T = serde_json::from_str(&serde_json::to_string(value).unwrap()).unwrap();
+        assert_eq!(&json, value, "JSON");
+        let yaml: T = serde_yaml::from_str(&serde_yaml::to_string(value).unwrap()).unwrap();
+        assert_eq!(&yaml, value, "YAML");
+        let mut cbor = Vec::new();
+        ciborium::into_writer(value, &mut cbor).unwrap();
+        assert_eq!(&ciborium::from_reader::<T, _>(cbor.as_slice()).unwrap(), value, "CBOR");
+        let bincode: T = bincode::deserialize(&bincode::serialize(value).unwrap()).unwrap();
+        assert_eq!(&bincode, value, "bincode");
+    }
+
+    #[rstest]
+    #[case("")]
+    #[case("A")]
+    #[case("A&B")]
+    #[case("A|(B&\"label 🕺\")")]
+    #[case("(A&(B|C))|D")]
+    fn round_trips_in_every_format(#[case] expression: &str) {
+        round_trip(&cell(expression));
+    }
+
+    #[test]
+    fn access_expressions_keep_their_text() {
+        let expression = AccessExpression::of("B|A").unwrap();
+        round_trip(&expression);
+        assert_eq!(serde_json::to_value(&expression).unwrap(), "B|A");
+        assert!(serde_json::from_str::<AccessExpression>(r#""A&B|C""#).is_err());
+    }
+
+    #[test]
+    fn human_readable_representations() {
+        let cell = cell("A&(B|C)");
+        let json = serde_json::to_value(&cell).unwrap();
+        assert_eq!(json["visibility"], cell.visibility.to_expression_str());
+        assert_eq!(json["tree"], cell.tree.to_json());
+        assert_eq!(json["authorizations"], serde_json::json!(["A", "B"]));
+    }
+
+    #[test]
+    fn empty_expression_is_null_in_the_tree() {
+        let json = serde_json::to_value(cell("")).unwrap();
+        assert_eq!(json["visibility"], "");
+        assert_eq!(json["tree"], serde_json::Value::Null);
+    }
+
+    #[rstest]
+    #[case(r#"{"visibility": "A&B|C", "tree": null, "authorizations": []}"#, "invalid access expression")]
+    #[case(r#"{"visibility": "A", "tree": {"and": []}, "authorizations": []}"#, "at least one operand")]
+    #[case(r#"{"visibility": "A", "tree": {"and": ["A", null]}, "authorizations": []}"#, "null")]
+    #[case(r#"{"visibility": "A", "tree": {"and": ["A"], "or": ["B"]}, "authorizations": []}"#, "both")]
+    #[case(r#"{"visibility": "A", "tree": {"xor": ["A"]}, "authorizations": []}"#, "xor")]
+    #[case(r#"{"visibility": "A", "tree": {}, "authorizations": []}"#, "key")]
+    #[case(r#"{"visibility": "A", "tree": "", "authorizations": []}"#, "empty")]
+    #[case(r#"{"visibility": "A", "tree": {"or": ["A", "a\nb"]}, "authorizations": []}"#, "at /or/1: labels must not contain control characters")]
+    #[case(r#"{"visibility": "A", "tree": 42, "authorizations": []}"#, "a label")]
+    #[case(r#"{"visibility": "A", "tree": {"or": "A"}, "authorizations": []}"#, "array of operands")]
+    fn invalid_input_is_rejected(#[case] json: &str, #[case] message: &str) {
+        let err = serde_json::from_str::<Cell>(json).unwrap_err().to_string();
+        assert!(err.contains(message), "{}", err);
+    }
+
+    #[rstest]
+    #[case(AuthorizationExpression::ConjunctionOf(vec![AuthorizationExpression::Nil]))]
+    #[case(AuthorizationExpression::DisjunctionOf(vec![]))]
+    #[case(AuthorizationExpression::AccessToken("tab\t".to_string()))]
+    fn binary_trees_are_validated(#[case] invalid: AuthorizationExpression) {
+        #[derive(Deserialize)]
+        struct Wrapped(#[serde(with = "tree")] #[allow(dead_code)] AuthorizationExpression);
+
+        let bytes = bincode::serialize(&Tree(&invalid)).unwrap();
+        assert!(bincode::deserialize::<Wrapped>(&bytes).is_err());
+    }
+}
@@ -0,0 +1,302 @@
+// Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
+// Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE_MIT or LICENSE_APACHE files.
+
+//! Metrics about evaluations, parsing and caching.
+//!
+//! Every measurement is emitted through the [`metrics`](https://docs.rs/metrics) facade, so it ends up
+//! in whatever recorder the application installed. The crate also keeps its own totals, which
+//! [`render_prometheus`] renders in the Prometheus text exposition format without any recorder.
+//!
+//! | Metric | Type | Labels |
+//! |---|---|---|
+//! | `accumulo_access_evaluations_total` | counter | `decision` (`granted`, `denied`) |
+//! | `accumulo_access_parse_failures_total` | counter | `error` (the `ParserError` variant) |
+//! | `accumulo_access_parse_duration_seconds` | histogram | |
+//! | `accumulo_access_cache_hits_total` | counter | `cache` (`decision`, `expression`) |
+//! | `accumulo_access_cache_misses_total` | counter | `cache` |
+//! | `accumulo_access_cache_evictions_total` | counter | `cache` |
+//! | `accumulo_access_cache_entries` | gauge | `cache` (only rendered with the `caching` feature) |
+//! | `accumulo_access_cache_bytes` | gauge | `cache` (only rendered with the `caching` feature) |
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::ParserError;
+
+const EVALUATIONS: &str = "accumulo_access_evaluations_total";
+const PARSE_FAILURES: &str = "accumulo_access_parse_failures_total";
+const PARSE_DURATION: &str = "accumulo_access_parse_duration_seconds";
+const CACHE_HITS: &str = "accumulo_access_cache_hits_total";
+const CACHE_MISSES: &str = "accumulo_access_cache_misses_total";
+const CACHE_EVICTIONS: &str = "accumulo_access_cache_evictions_total";
+
+/// The upper bounds of the parse duration histogram buckets, in seconds.
+const PARSE_DURATION_BUCKETS: [f64; 10] = [1e-6, 5e-6, 1e-5, 5e-5, 1e-4, 5e-4, 1e-3, 5e-3, 1e-2, 1e-1];
+
+const PARSER_ERRORS: [&str; 5] = ["empty_scope", "missing_operator", "unexpected_token", "mixing_operators", "lexer_error"];
+
+/// The caches whose behaviour is measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Cache {
+    Decision,
+    Expression,
+}
+
+impl Cache {
+    const ALL: [Cache; 2] = [Cache::Decision, Cache::Expression];
+
+    fn label(self) -> &'static str {
+        match self {
+            Cache::Decision => "decision",
+            Cache::Expression => "expression",
+        }
+    }
+}
+
+struct CacheTotals {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+struct Totals {
+    granted: AtomicU64,
+    denied: AtomicU64,
+    parse_failures: [AtomicU64; PARSER_ERRORS.len()],
+    /// Non-cumulative counts per bucket; the last one counts the durations above every bound.
+    parse_buckets: [AtomicU64; PARSE_DURATION_BUCKETS.len() + 1],
+    parse_nanos: AtomicU64,
+    caches: [CacheTotals; Cache::ALL.len()],
+}
+
+static TOTALS: Totals = Totals {
+    granted: AtomicU64::new(0),
+    denied: AtomicU64::new(0),
+    parse_failures: [const { AtomicU64::new(0) }; PARSER_ERRORS.len()],
+    parse_buckets: [const { AtomicU64::new(0) }; PARSE_DURATION_BUCKETS.len() + 1],
+    parse_nanos: AtomicU64::new(0),
+    caches: [const { CacheTotals { hits: AtomicU64::new(0), misses: AtomicU64::new(0), evictions: AtomicU64::new(0) } }; Cache::ALL.len()],
+};
+
+fn error_index(error: &ParserError) -> usize {
+    match error {
+        ParserError::EmptyScope => 0,
+        ParserError::MissingOperator => 1,
+        ParserError::UnexpectedToken(_) => 2,
+        ParserError::MixingOperators => 3,
+        ParserError::LexerError(_) => 4,
+    }
+}
+
+pub(crate) fn record_evaluation(granted: bool) {
+    let (total, decision) = match granted {
+        true => (&TOTALS.granted, "granted"),
+        false => (&TOTALS.denied, "denied"),
+    };
+    total.fetch_add(1, Ordering::Relaxed);
+    metrics::counter!(EVALUATIONS, "decision" => decision).increment(1);
+}
+
+pub(crate) fn record_parse(elapsed: Duration, error: Option<&ParserError>) {
+    let seconds = elapsed.as_secs_f64();
+    let bucket = PARSE_DURATION_BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(PARSE_DURATION_BUCKETS.len());
+    TOTALS.parse_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    TOTALS.parse_nanos.fetch_add(u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX), Ordering::Relaxed);
+    metrics::histogram!(PARSE_DURATION).record(seconds);
+
+    if let Some(error) = error {
+        let index = error_index(error);
+        TOTALS.parse_failures[index].fetch_add(1, Ordering::Relaxed);
+        metrics::counter!(PARSE_FAILURES, "error" => PARSER_ERRORS[index]).increment(1);
+    }
+}
+
+#[cfg(feature = "caching")]
+pub(crate) fn record_cache_lookup(cache: Cache, hit: bool) {
+    let totals = &TOTALS.caches[cache as usize];
+    if hit {
+        totals.hits.fetch_add(1, Ordering::Relaxed);
+        metrics::counter!(CACHE_HITS, "cache" => cache.label()).increment(1);
+    } else {
+        totals.misses.fetch_add(1, Ordering::Relaxed);
+        metrics::counter!(CACHE_MISSES, "cache" => cache.label()).increment(1);
+    }
+}
+
+#[cfg(feature = "caching")]
+pub(crate) fn record_evictions(cache: Cache, evictions: usize) {
+    if evictions == 0 {
+        return;
+    }
+    TOTALS.caches[cache as usize].evictions.fetch_add(evictions as u64, Ordering::Relaxed);
+    metrics::counter!(CACHE_EVICTIONS, "cache" => cache.label()).increment(evictions as u64);
+}
+
+fn load(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Renders the totals recorded by this process in the Prometheus text exposition format (version 0.0.4).
+///
+/// With the `caching` feature, the current size of the process-wide caches is included as well; a
+/// decision cache that hasn't been used yet is reported as empty.
+///
+/// # Example
+/// ```
+/// use accumulo_access::{check_authorization, telemetry};
+///
+/// check_authorization("A&B", &["A".to_string()]).unwrap();
+/// let _ = check_authorization("A&B|C", &["A".to_string()]);
+///
+/// let snapshot = telemetry::render_prometheus();
+/// assert!(snapshot.contains("accumulo_access_evaluations_total{decision=\"denied\"}"));
+/// assert!(snapshot.contains("accumulo_access_parse_failures_total{error=\"mixing_operators\"}"));
+/// ```
+pub fn render_prometheus() -> String {
+    let mut out = String::new();
+
+    write_header(&mut out, EVALUATIONS, "counter", "Evaluated authorization expressions, by decision.");
+    let _ = writeln!(out, "{}{{decision=\"granted\"}} {}", EVALUATIONS, load(&TOTALS.granted));
+    let _ = writeln!(out, "{}{{decision=\"denied\"}} {}", EVALUATIONS, load(&TOTALS.denied));
+
+    write_header(&mut out, PARSE_FAILURES, "counter", "Expressions that failed to parse, by error.");
+    for (error, total) in PARSER_ERRORS.iter().zip(&TOTALS.parse_failures) {
+        let _ = writeln!(out, "{}{{error=\"{}\"}} {}", PARSE_FAILURES, error, load(total));
+    }
+
+    write_header(&mut out, PARSE_DURATION, "histogram", "Time spent parsing expressions.");
+    let mut count = 0;
+    for (bound, total) in PARSE_DURATION_BUCKETS.iter().zip(&TOTALS.parse_buckets) {
+        count += load(total);
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", PARSE_DURATION, bound, count);
+    }
+    count += load(&TOTALS.parse_buckets[PARSE_DURATION_BUCKETS.len()]);
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", PARSE_DURATION, count);
+    let _ = writeln!(out, "{}_sum {}", PARSE_DURATION, load(&TOTALS.parse_nanos) as f64 / 1e9);
+    let _ = writeln!(out, "{}_count {}", PARSE_DURATION, count);
+
+    for (name, help, counter) in [
+        (CACHE_HITS, "Cache lookups that found an entry.", (|totals: &CacheTotals| load(&totals.hits)) as fn(&CacheTotals) -> u64),
+        (CACHE_MISSES, "Cache lookups that found no entry.", |totals| load(&totals.misses)),
+        (CACHE_EVICTIONS, "Entries evicted to make room for new ones.", |totals| load(&totals.evictions)),
+    ] {
+        write_header(&mut out, name, "counter", help);
+        for cache in Cache::ALL {
+            let _ = writeln!(out, "{}{{cache=\"{}\"}} {}", name, cache.label(), counter(&TOTALS.caches[cache as usize]));
+        }
+    }
+
+    #[cfg(feature = "caching")]
+    {
+        let stats = [
+            // Reading the stats must not create the decision cache, which would keep another backend
+            // from being installed.
+            (Cache::Decision, crate::caching::installed_authz_cache_stats().unwrap_or_else(|| crate::caching::AuthzCacheStats::new(0, 0, 0))),
+            (Cache::Expression, crate::caching::expression_cache_stats()),
+        ];
+        write_header(&mut out, "accumulo_access_cache_entries", "gauge", "Entries in the process-wide caches.");
+        for (cache, stats) in &stats {
+            let _ = writeln!(out, "accumulo_access_cache_entries{{cache=\"{}\"}} {}", cache.label(), stats.size);
+        }
+        write_header(&mut out, "accumulo_access_cache_bytes", "gauge", "Estimated memory used by the process-wide caches.");
+        for (cache, stats) in &stats {
+            let _ = writeln!(out, "accumulo_access_cache_bytes{{cache=\"{}\"}} {}", cache.label(), stats.bytes);
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Lexer, Parser};
+
+    // The totals are process-wide and other tests run concurrently, so only lower bounds are checked.
+    fn value(snapshot: &str, series: &str) -> f64 {
+        snapshot
+            .lines()
+            .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+            .unwrap_or_else(|| panic!("{} missing from\n{}", series, snapshot))
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn parse_failures_are_counted_by_variant() {
+        let before = render_prometheus();
+        assert!(Parser::new(Lexer::new("A&B|C")).parse().is_err());
+        assert!(Parser::new(Lexer::new("A[")).parse().is_err());
+        let after = render_prometheus();
+
+        for series in [
+            "accumulo_access_parse_failures_total{error=\"mixing_operators\"}",
+            "accumulo_access_parse_failures_total{error=\"lexer_error\"}",
+        ] {
+            assert!(value(&after, series) >= value(&before, series) + 1.0, "{}", series);
+        }
+        let count = "accumulo_access_parse_duration_seconds_count";
+        assert!(value(&after, count) >= value(&before, count) + 2.0);
+    }
+
+    #[test]
+    fn evaluations_are_counted_by_decision() {
+        let before = render_prometheus();
+        assert_eq!(crate::check_authorization("A|B", &["B".to_string()]), Ok(true));
+        assert_eq!(crate::check_authorization("A&B", &["B".to_string()]), Ok(false));
+        let after = render_prometheus();
+
+        for series in ["accumulo_access_evaluations_total{decision=\"granted\"}", "accumulo_access_evaluations_total{decision=\"denied\"}"] {
+            assert!(value(&after, series) >= value(&before, series) + 1.0, "{}", series);
+        }
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        record_parse(Duration::from_secs(1), None);
+        let snapshot = render_prometheus();
+        let buckets: Vec<f64> = snapshot
+            .lines()
+            .filter(|line| line.starts_with("accumulo_access_parse_duration_seconds_bucket"))
+            .map(|line| line.rsplit(' ').next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(buckets.len(), PARSE_DURATION_BUCKETS.len() + 1);
+        assert!(buckets.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(buckets[buckets.len() - 1] > buckets[buckets.len() - 2]);
+    }
+
+    #[test]
+    fn every_series_has_a_type() {
+        for line in render_prometheus().lines().filter(|line| !line.starts_with('#')) {
+            let name = line.split(['{', ' ']).next().unwrap();
+            let family = name.trim_end_matches("_bucket").trim_end_matches("_sum").trim_end_matches("_count");
+            assert!(render_prometheus().contains(&format!("# TYPE {} ", family)), "{}", line);
+        }
+    }
+
+    #[cfg(feature = "caching")]
+    #[test]
+    fn cache_lookups_and_evictions_are_counted() {
+        let before = render_prometheus();
+        let cache = crate::caching::AuthorizationCache::new(1).with_shards(1);
+        cache.check_authorization("A", &["A".to_string()]).unwrap();
+        cache.check_authorization("A", &["A".to_string()]).unwrap();
+        cache.check_authorization("B", &["A".to_string()]).unwrap();
+        let after = render_prometheus();
+
+        for series in [
+            "accumulo_access_cache_hits_total{cache=\"decision\"}",
+            "accumulo_access_cache_evictions_total{cache=\"decision\"}",
+        ] {
+            assert!(value(&after, series) >= value(&before, series) + 1.0, "{}", series);
+        }
+        let misses = "accumulo_access_cache_misses_total{cache=\"decision\"}";
+        assert!(value(&after, misses) >= value(&before, misses) + 2.0);
+    }
+}
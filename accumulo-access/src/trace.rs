@@ -0,0 +1,274 @@
+// Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
+// Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE_MIT or LICENSE_APACHE files.
+
+//! [`tracing`](https://docs.rs/tracing) instrumentation of parsing, evaluation and caching.
+//!
+//! With the `tracing` feature, the crate emits the following spans (target `accumulo_access`):
+//!
+//! | Span | Level | Fields |
+//! |---|---|---|
+//! | `parse` | trace | `expression.fingerprint`, `expression.length`, `expression`, `label_count`, `error` |
+//! | `evaluate` | trace | `expression.fingerprint`, `expression`, `label_count`, `authorization_count`, `decision` |
+//! | `check_authorization` | debug | `expression.fingerprint`, `authorization_count`, `decision`, `error` |
+//! | `decision_cache` | debug | `expression.fingerprint`, `cache_hit`, `decision` |
+//! | `expression_cache` | debug | `expression.fingerprint`, `cache_hit` |
+//!
+//! Labels can be sensitive themselves, so by default they are redacted: the `expression` field
+//! holds the parsed expression with every label replaced by a salted hash, and raw expression text
+//! is never logged. The fingerprint is a (salted) hash of the expression text as the caller gave
+//! it, the same in every span, which correlates the spans of the same expression without revealing
+//! it; a tree evaluated without any text is fingerprinted by its [`to_expression_str`] form. Use
+//! [`set_redaction`] to log labels in plain text instead, or to change the salt.
+//!
+//! The hashes are 64-bit FNV-1a, which is stable across builds and platforms but not meant to
+//! withstand guessing; keep the salt secret if labels come from a small, known vocabulary.
+//!
+//! [`to_expression_str`]: AuthorizationExpression::to_expression_str
+
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::hashing::Fnv1a;
+use crate::AuthorizationExpression;
+
+/// How labels are written to spans.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Redaction {
+    /// Labels and expressions are logged as they are.
+    Plain,
+    /// Labels are replaced by their hash, mixed with the given salt (the default, with a salt of 0).
+    Hashed { salt: u64 },
+}
+
+static PLAIN: AtomicBool = AtomicBool::new(false);
+static SALT: AtomicU64 = AtomicU64::new(0);
+
+/// Sets how labels are written to spans, for the whole process.
+///
+/// # Example
+/// ```
+/// use accumulo_access::trace::{self, Redaction};
+///
+/// trace::set_redaction(Redaction::Hashed { salt: 0x5eed });
+/// assert_eq!(trace::redaction(), Redaction::Hashed { salt: 0x5eed });
+/// ```
+pub fn set_redaction(redaction: Redaction) {
+    match redaction {
+        Redaction::Plain => PLAIN.store(true, Ordering::Relaxed),
+        Redaction::Hashed { salt } => {
+            SALT.store(salt, Ordering::Relaxed);
+            PLAIN.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+pub fn redaction() -> Redaction {
+    match PLAIN.load(Ordering::Relaxed) {
+        true => Redaction::Plain,
+        false => Redaction::Hashed { salt: SALT.load(Ordering::Relaxed) },
+    }
+}
+
+fn hash(salt: u64, text: &str) -> u64 {
+    let mut hasher = Fnv1a::with_salt(salt);
+    hasher.write(text.as_bytes());
+    hasher.finish()
+}
+
+fn salt() -> u64 {
+    match redaction() {
+        Redaction::Plain => 0,
+        Redaction::Hashed { salt } => salt,
+    }
+}
+
+/// The fingerprint of an expression's text.
+pub(crate) fn fingerprint(expression: &str) -> String {
+    format!("{:016x}", hash(salt(), expression))
+}
+
+/// The label as it may be written to a span.
+pub(crate) fn label(label: &str) -> Cow<'_, str> {
+    match redaction() {
+        Redaction::Plain => Cow::Borrowed(label),
+        Redaction::Hashed { salt } => Cow::Owned(format!("#{:016x}", hash(salt, label))),
+    }
+}
+
+fn redact(expr: &AuthorizationExpression) -> AuthorizationExpression {
+    match expr {
+        AuthorizationExpression::AccessToken(token) => AuthorizationExpression::AccessToken(label(token).into_owned()),
+        AuthorizationExpression::ConjunctionOf(nodes) => AuthorizationExpression::ConjunctionOf(nodes.iter().map(redact).collect()),
+        AuthorizationExpression::DisjunctionOf(nodes) => AuthorizationExpression::DisjunctionOf(nodes.iter().map(redact).collect()),
+        AuthorizationExpression::Nil => AuthorizationExpression::Nil,
+    }
+}
+
+/// The parsed expression as it may be written to a span.
+pub(crate) fn expression(expr: &AuthorizationExpression) -> String {
+    match redaction() {
+        Redaction::Plain => expr.to_expression_str(),
+        Redaction::Hashed { .. } => redact(expr).to_expression_str(),
+    }
+}
+
+/// The number of distinct labels referenced by the expression.
+pub(crate) fn label_count(expr: &AuthorizationExpression) -> usize {
+    fn collect<'a>(expr: &'a AuthorizationExpression, labels: &mut BTreeSet<&'a str>) {
+        match expr {
+            AuthorizationExpression::AccessToken(token) => {
+                labels.insert(token);
+            }
+            AuthorizationExpression::ConjunctionOf(nodes) | AuthorizationExpression::DisjunctionOf(nodes) => {
+                nodes.iter().for_each(|node| collect(node, labels));
+            }
+            AuthorizationExpression::Nil => {}
+        }
+    }
+    let mut labels = BTreeSet::new();
+    collect(expr, &mut labels);
+    labels.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Lexer, Parser};
+    use std::collections::HashMap;
+    use std::fmt::Debug;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    type Fields = HashMap<String, String>;
+
+    /// The name and recorded fields of every span, by span id.
+    #[derive(Default, Clone)]
+    struct Recorder {
+        next_id: Arc<AtomicU64>,
+        spans: Arc<Mutex<HashMap<u64, (&'static str, Fields)>>>,
+    }
+
+    impl Recorder {
+        fn spans(&self, name: &str) -> Vec<Fields> {
+            let spans = self.spans.lock().unwrap();
+            let mut ids: Vec<&u64> = spans.keys().filter(|id| spans[id].0 == name).collect();
+            ids.sort();
+            ids.into_iter().map(|id| spans[id].1.clone()).collect()
+        }
+    }
+
+    struct FieldVisitor<'a>(&'a mut Fields);
+
+    impl Visit for FieldVisitor<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0.insert(field.name().to_string(), format!("{:?}", value));
+        }
+
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+            let mut fields = HashMap::new();
+            span.record(&mut FieldVisitor(&mut fields));
+            self.spans.lock().unwrap().insert(id, (span.metadata().name(), fields));
+            Id::from_u64(id)
+        }
+
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            let mut spans = self.spans.lock().unwrap();
+            values.record(&mut FieldVisitor(&mut spans.get_mut(&span.into_u64()).unwrap().1));
+        }
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, _: &Event<'_>) {}
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    fn tokens(labels: &[&str]) -> Vec<String> {
+        labels.iter().map(|label| label.to_string()).collect()
+    }
+
+    // Both modes are checked in one test, as the redaction setting is process-wide.
+    #[test]
+    fn spans_carry_decisions_and_redact_labels() {
+        let recorder = Recorder::default();
+        tracing::subscriber::with_default(recorder.clone(), || {
+            set_redaction(Redaction::Hashed { salt: 42 });
+            assert_eq!(crate::check_authorization("secret&(A|B)", &tokens(&["secret", "B"])), Ok(true));
+            set_redaction(Redaction::Plain);
+            assert_eq!(crate::check_authorization("secret&(A|B)", &tokens(&["A"])), Ok(false));
+            set_redaction(Redaction::Hashed { salt: 0 });
+        });
+
+        let checks = recorder.spans("check_authorization");
+        assert_eq!(checks.len(), 2);
+        assert_eq!(checks[0]["decision"], "true");
+        assert_eq!(checks[1]["decision"], "false");
+        assert_ne!(checks[0]["expression.fingerprint"], checks[1]["expression.fingerprint"], "the salt changes the fingerprint");
+
+        let parses = recorder.spans("parse");
+        assert_eq!(parses[0]["label_count"], "3");
+        assert!(!parses[0]["expression"].contains("secret"), "{}", parses[0]["expression"]);
+        assert!(parses[1]["expression"].starts_with("secret&"), "{}", parses[1]["expression"]);
+
+        let evaluations = recorder.spans("evaluate");
+        assert_eq!(evaluations[0]["authorization_count"], "2");
+        assert_eq!(evaluations[0]["decision"], "true");
+        assert!(!evaluations[0]["expression"].contains("secret"));
+        assert!(evaluations[1]["expression"].starts_with("secret&"), "{}", evaluations[1]["expression"]);
+
+        for i in 0..2 {
+            assert_eq!(parses[i]["expression.fingerprint"], checks[i]["expression.fingerprint"]);
+            assert_eq!(evaluations[i]["expression.fingerprint"], checks[i]["expression.fingerprint"]);
+        }
+    }
+
+    #[test]
+    fn parse_errors_are_recorded() {
+        let recorder = Recorder::default();
+        tracing::subscriber::with_default(recorder.clone(), || {
+            assert!(Parser::new(Lexer::new("A&B|C")).parse().is_err());
+        });
+        let parses = recorder.spans("parse");
+        assert_eq!(parses.len(), 1);
+        assert_eq!(parses[0]["error"], "Mixing operators");
+        assert_eq!(parses[0]["expression.length"], "5");
+    }
+
+    #[cfg(feature = "caching")]
+    #[test]
+    fn cache_spans_record_hits() {
+        let recorder = Recorder::default();
+        let cache = crate::caching::AuthorizationCache::new(10);
+        tracing::subscriber::with_default(recorder.clone(), || {
+            cache.check_authorization("A", &tokens(&["A"])).unwrap();
+            cache.check_authorization("A", &tokens(&["A"])).unwrap();
+        });
+        let lookups = recorder.spans("decision_cache");
+        assert_eq!(lookups.len(), 2);
+        assert_eq!((lookups[0]["cache_hit"].as_str(), lookups[1]["cache_hit"].as_str()), ("false", "true"));
+        assert_eq!(lookups[1]["decision"], "true");
+        assert_eq!(lookups[0]["expression.fingerprint"], recorder.spans("evaluate")[0]["expression.fingerprint"]);
+    }
+
+    #[test]
+    fn label_count_ignores_duplicates() {
+        assert_eq!(label_count(&Parser::new(Lexer::new("A&(A|B)")).parse().unwrap()), 2);
+    }
+}
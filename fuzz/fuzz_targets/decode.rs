@@ -0,0 +1,22 @@
+// Copyright 2024 Lars Wilhelmsen <sral-backwards@sral.org>. All rights reserved.
+// Use of this source code is governed by the MIT or Apache-2.0 license that can be found in the LICENSE-MIT or LICENSE-APACHE files.
+
+#![no_main]
+extern crate accumulo_access;
+
+use std::collections::HashSet;
+
+use accumulo_access::{AuthorizationExpression, EncodedExpression};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(encoded) = EncodedExpression::new(data) else {
+        return;
+    };
+    let expr = encoded.to_expression();
+
+    // Every other label is granted, so both outcomes are exercised.
+    let authorizations: HashSet<String> = encoded.labels().iter().step_by(2).map(|label| label.to_string()).collect();
+    assert_eq!(encoded.evaluate(&authorizations), expr.evaluate(&authorizations));
+    assert_eq!(AuthorizationExpression::decode(&expr.encode().unwrap()).as_ref(), Ok(&expr));
+});